@@ -1,12 +1,22 @@
 use anyhow::{Context, Result};
-use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use indicatif::{ProgressBar, ProgressStyle};
 use once_cell::sync::Lazy;
+use rayon::prelude::*;
 use regex::{Captures, Regex, RegexBuilder};
-use std::collections::HashSet;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::fs::{self, File};
-use std::io::{BufRead, BufReader, Read}; // Removed unused Write
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, IsTerminal, Read, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use walkdir::WalkDir;
 
 // ============================================================================
@@ -17,7 +27,111 @@ const MAX_FILE_SIZE: u64 = 50 * 1024 * 1024;
 const DEFAULT_TREE_DEPTH: usize = 20;
 const ABSOLUTE_MAX_DEPTH: usize = 100;
 const DEFAULT_LIMIT: usize = 110_000;
+/// Files above this are emitted uncleaned, so one huge file can't stall the comment regexes.
+const MAX_CLEAN_SIZE: u64 = 5 * 1024 * 1024;
 const DEFAULT_OUT_PATTERN: &str = "dump/dump_*.txt";
+const DEFAULT_OUT_NAME: &str = "dump_*.txt";
+const DEFAULT_HEADER_TEMPLATE: &str = "--- FILE: {relpath}{notes} ---";
+/// Written beside the chunks on every run; wiped with them unless `--append` reads it.
+const MANIFEST_NAME: &str = ".dump-manifest";
+const WRAP_MARKER: &str = "↪ ";
+const BASE64_MAX_SIZE: &str = "65536";
+/// Longest file name most filesystems accept, in bytes.
+const NAME_MAX: usize = 255;
+/// `--clipboard` warns above this; many targets choke on pastes this large.
+const CLIPBOARD_WARN_SIZE: usize = 4 * 1024 * 1024;
+const DEFAULT_KEEP_MARKERS: [&str; 4] = ["TODO", "FIXME", "HACK", "XXX"];
+const GENERATED_SCAN_BYTES: usize = 4096;
+const GENERATED_MARKERS: &[&str] = &[
+    "@generated",
+    "DO NOT EDIT",
+    "Code generated by",
+    "<auto-generated",
+    "This file is automatically generated",
+];
+
+/// Extensions whose formats have no comment syntax, so `--path-as-comment` leaves them alone.
+const NO_COMMENT_EXTS: &[&str] = &["json", "md", "markdown", "txt", "csv", "tsv", "lock"];
+
+/// Ignore files shared with ripgrep and fd, read from the source root unless `--no-ignore-files`.
+const IGNORE_FILES: &[&str] = &[".ignore", ".rgignore"];
+
+/// `--smart-excludes`: build-output and dependency directories keyed by the root file that
+/// identifies each ecosystem.
+const SMART_EXCLUDES: &[(&str, &[&str])] = &[
+    (
+        "package.json",
+        &["node_modules", "dist", ".next", "coverage"],
+    ),
+    ("Cargo.toml", &["target"]),
+    ("composer.json", &["vendor", "storage/logs"]),
+    (
+        "pyproject.toml",
+        &["__pycache__", ".venv", "venv", "*.pyc", ".pytest_cache"],
+    ),
+    (
+        "requirements.txt",
+        &["__pycache__", ".venv", "venv", "*.pyc", ".pytest_cache"],
+    ),
+    (
+        "setup.py",
+        &["__pycache__", ".venv", "venv", "*.pyc", ".pytest_cache"],
+    ),
+    ("go.mod", &["vendor"]),
+    ("pom.xml", &["target"]),
+    ("build.gradle", &["build", ".gradle"]),
+];
+
+/// `--detect-language`: root files that give away the project's main comment syntax, used
+/// when no collected file has a recognised extension.
+const PROJECT_MANIFESTS: &[(&str, CommentStyle)] = &[
+    ("Cargo.toml", CommentStyle::C),
+    ("package.json", CommentStyle::C),
+    ("go.mod", CommentStyle::C),
+    ("pom.xml", CommentStyle::C),
+    ("build.gradle", CommentStyle::C),
+    ("composer.json", CommentStyle::Php),
+    ("pyproject.toml", CommentStyle::Python),
+    ("requirements.txt", CommentStyle::Python),
+    ("setup.py", CommentStyle::Python),
+    ("Gemfile", CommentStyle::Script),
+];
+
+const LANGUAGES: &[(&str, &str)] = &[
+    ("rs", "Rust"),
+    ("php", "PHP"),
+    ("py", "Python"),
+    ("js", "JavaScript"),
+    ("mjs", "JavaScript"),
+    ("cjs", "JavaScript"),
+    ("jsx", "JavaScript"),
+    ("ts", "TypeScript"),
+    ("tsx", "TypeScript"),
+    ("go", "Go"),
+    ("java", "Java"),
+    ("kt", "Kotlin"),
+    ("swift", "Swift"),
+    ("c", "C"),
+    ("h", "C"),
+    ("cpp", "C++"),
+    ("cc", "C++"),
+    ("hpp", "C++"),
+    ("cs", "C#"),
+    ("rb", "Ruby"),
+    ("sh", "Shell"),
+    ("bash", "Shell"),
+    ("sql", "SQL"),
+    ("html", "HTML"),
+    ("css", "CSS"),
+    ("scss", "SCSS"),
+    ("vue", "Vue"),
+    ("md", "Markdown"),
+    ("json", "JSON"),
+    ("yml", "YAML"),
+    ("yaml", "YAML"),
+    ("toml", "TOML"),
+    ("xml", "XML"),
+];
 
 const DEFAULT_CONFIG: &str = r#"# Source Dumper Configuration (.dumperrc)
 
@@ -38,6 +152,13 @@ exclude = .git, target, node_modules, .idea, .vscode, *.lock, *.log
 # tree_depth = 20
 # show_size = false
 # hidden = false
+# sort = path
+# reverse = false
+
+# Named profiles are applied over the settings above with --profile NAME
+# [profile.review]
+# clean = true
+# include = README.md
 "#;
 
 // ============================================================================
@@ -54,6 +175,35 @@ static SCRIPT_STYLE_REGEX: Lazy<Regex> = Lazy::new(|| {
         .build().expect("Script regex")
 });
 
+/// Python strings: triple-quoted (with escapes), and f-strings whose `{..}` fields may hold the
+/// same quote (allowed since 3.12, e.g. `f"{d["k#"]}"`), one level of nested braces deep.
+/// Prefixes (`r`, `b`, `u`, `f` and pairs) are matched so a raw `r"\"` can't leak.
+static PYTHON_STYLE_REGEX: Lazy<Regex> = Lazy::new(|| {
+    let triple = |q: &str| {
+        format!(
+            r#"(?i:[rbuf]{{0,2}}){q}{q}{q}(?:[^{q}\\]|\\[\s\S]|{q}(?:[^{q}\\]|\\[\s\S])|{q}{q}(?:[^{q}\\]|\\[\s\S]))*{q}{q}{q}"#
+        )
+    };
+    let fstring = |q: &str| {
+        format!(
+            r#"(?i:[rb]?f[rb]?){q}(?:[^{q}\\{{\n]|\\.|\{{[^{{}}\n]*(?:\{{[^{{}}\n]*\}}[^{{}}\n]*)*\}})*{q}"#
+        )
+    };
+    let plain = |q: &str| format!(r#"(?i:[rbu]{{0,2}}){q}[^{q}\\\n]*(?:\\.[^{q}\\\n]*)*{q}"#);
+    let keep = [
+        triple("\""),
+        triple("'"),
+        fstring("\""),
+        fstring("'"),
+        plain("\""),
+        plain("'"),
+    ]
+    .join("|");
+    RegexBuilder::new(&format!(r"(?P<keep>{})|(?P<drop>#.*)", keep))
+        .build()
+        .expect("Python regex")
+});
+
 static PHP_STYLE_REGEX: Lazy<Regex> = Lazy::new(|| {
     RegexBuilder::new(r#"(?P<keep>`[^`\\]*(?:\\.[^`\\]*)*`|"[^"\\]*(?:\\.[^"\\]*)*"|'[^'\\]*(?:\\.[^'\\]*)*')|(?P<drop>/\*[\s\S]*?\*/|//.*|#.*)"#)
         .build().expect("PHP regex")
@@ -72,6 +222,121 @@ static HTML_STYLE_REGEX: Lazy<Regex> = Lazy::new(|| {
     .expect("HTML regex")
 });
 
+/// `--strip-imports`: import statements per extension. Multi-line forms (`use a::{..};`,
+/// Go's `import (..)`, Python's parenthesised `from x import (..)`) are matched whole.
+static IMPORT_PATTERNS: Lazy<Vec<(&[&str], Regex)>> = Lazy::new(|| {
+    let patterns: [(&[&str], &str); 9] = [
+        (
+            &["rs"],
+            r"(?m)^[ \t]*(?:(?:pub(?:\([^)]*\))?\s+)?use\s[^;]*;|extern\s+crate\s[^;]*;)[ \t]*\n?",
+        ),
+        (
+            &["py"],
+            r"(?m)^[ \t]*(?:import\s.*|from\s+\S+\s+import\s+(?:\([^)]*\)|.*))\n?",
+        ),
+        (
+            &["js", "jsx", "ts", "tsx", "mjs", "cjs"],
+            r#"(?m)^[ \t]*(?:import\b[^;]*?['"][^'"\n]*['"][ \t]*;?|(?:const|let|var)\s+[^=\n]+=\s*require\([^)]*\)\s*;?)[ \t]*\n?"#,
+        ),
+        (
+            &["go"],
+            r#"(?m)^import\s*(?:\([^)]*\)|(?:\w+\s+)?"[^"\n]*")[ \t]*\n?"#,
+        ),
+        (
+            &["java", "kt", "kts", "scala", "swift"],
+            r"(?m)^[ \t]*import\s[^\n]*\n?",
+        ),
+        (
+            &["c", "h", "cc", "cpp", "hpp", "cxx", "m"],
+            r"(?m)^[ \t]*#[ \t]*include\s*[<\x22][^\n]*\n?",
+        ),
+        (
+            &["php"],
+            r"(?m)^[ \t]*(?:use\s[^;]*;|(?:require|include)(?:_once)?\b[^;]*;)[ \t]*\n?",
+        ),
+        (
+            &["cs"],
+            r"(?m)^[ \t]*(?:global\s+)?using\s+(?:static\s+)?[\w.]+(?:\s*=\s*[\w.<>]+)?\s*;[ \t]*\n?",
+        ),
+        (&["rb"], r"(?m)^[ \t]*require(?:_relative)?\s[^\n]*\n?"),
+    ];
+    patterns
+        .iter()
+        .map(|(exts, p)| (*exts, Regex::new(p).expect("import regex")))
+        .collect()
+});
+
+/// `--outline`: lines that open a declaration, by extension. Brace languages only; Python
+/// and Ruby are handled by their own entries.
+static OUTLINE_PATTERNS: Lazy<Vec<(&[&str], Regex)>> = Lazy::new(|| {
+    let patterns: [(&[&str], &str); 9] = [
+        (
+            &["rs"],
+            r#"^\s*(?:pub(?:\([^)]*\))?\s+)?(?:(?:async|const|unsafe|default|extern\s+"[^"]*")\s+)*(?:fn|struct|enum|trait|impl|type|mod|union|macro_rules!)\b"#,
+        ),
+        (&["py", "pyi", "pyw"], r"^\s*(?:async\s+)?(?:def|class)\s"),
+        (
+            &["js", "jsx", "ts", "tsx", "mjs", "cjs"],
+            r"^\s*(?:export\s+(?:default\s+)?)?(?:(?:declare|abstract|async)\s+)*(?:function\*?|class|interface|type|enum|namespace)\s|^\s*export\s+(?:const|let)\s+\w+\s*(?::[^=]+)?=\s*(?:async\s*)?(?:\([^)]*\)|\w+)\s*=>",
+        ),
+        (&["go"], r"^(?:func|type)\s"),
+        (
+            &["java", "kt", "kts", "scala", "cs", "swift", "dart"],
+            r"^\s*(?:@\w+\s+)*(?:(?:public|private|protected|internal|static|final|abstract|sealed|override|open|data|async|virtual|partial|readonly)\s+)*(?:class|interface|enum|record|struct|fun|def|func|object|trait|protocol|extension)\s|^\s*(?:public|protected|private|internal)\s[^=;(]*\w\s*\(",
+        ),
+        (
+            &["c", "h", "cc", "cpp", "hpp", "cxx"],
+            r"^(?:typedef\s+)?(?:struct|class|enum|union|namespace)\s+\w+|^[A-Za-z_][\w\s\*&:<>,]*[\s\*&]\*?[\w:~]+\s*\([^;]*$",
+        ),
+        (
+            &["php"],
+            r"^\s*(?:(?:public|private|protected|static|abstract|final|readonly)\s+)*(?:function|class|interface|trait|enum)\s",
+        ),
+        (&["rb"], r"^\s*(?:def|class|module)\s"),
+        (
+            &["sh", "bash", "zsh"],
+            r"^\s*(?:function\s+\w+|\w+\s*\(\s*\))",
+        ),
+    ];
+    patterns
+        .iter()
+        .map(|(exts, p)| (*exts, Regex::new(p).expect("outline regex")))
+        .collect()
+});
+
+/// Built-in `--redact` patterns. When a pattern has a `secret` group only that part is
+/// masked, so the surrounding key name stays readable.
+static SECRET_PATTERNS: Lazy<Vec<Regex>> = Lazy::new(|| {
+    [
+        r"(?s)-----BEGIN [A-Z ]*PRIVATE KEY-----.*?-----END [A-Z ]*PRIVATE KEY-----",
+        r"\b(?:AKIA|ASIA)[0-9A-Z]{16}\b",
+        r"(?i)\bBearer\s+(?P<secret>[A-Za-z0-9\-._~+/]{8,}=*)",
+        r#"(?i)\b(?:password|passwd|pwd|secret|api[_-]?key|access[_-]?token|auth[_-]?token|client[_-]?secret)\b["']?\s*[:=]\s*["']?(?P<secret>[^\s"']+)"#,
+    ]
+    .iter()
+    .map(|p| Regex::new(p).expect("secret regex"))
+    .collect()
+});
+static TOKEN_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"[A-Za-z0-9+/=_\-]{32,}").unwrap());
+/// Skipped paths and reasons, kept only when `--report` asks for them. Skips are logged from
+/// walk filters and processing alike, so they are gathered here rather than threaded through.
+static SKIPPED: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// Set by the Ctrl-C handler once files are being processed. Processing then stops at the
+/// next file and writes what it has; a second Ctrl-C, or one before processing, exits at once.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static PROCESSING: AtomicBool = AtomicBool::new(false);
+
+/// Time spent per phase for `--timing`. Tree building and writing happen inside processing
+/// (streamed chunks are written between files), so phases add to their totals from anywhere.
+static TIMINGS: Mutex<Vec<(&'static str, Duration)>> = Mutex::new(Vec::new());
+
+static XML_GAP_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r">\s+<").unwrap());
+/// A YAML line introducing a `|` or `>` block scalar, with optional chomping/indent indicators.
+static YAML_BLOCK_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?:^|\s)[|>][-+1-9]*\s*(?:#.*)?$").unwrap());
+/// Stands in for a stripped comment under `--keep-empty-lines`; NUL never occurs in text files.
+const REMOVED_MARK: char = '\0';
 static EMPTY_LINES_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?m)(^\s*\n)+").unwrap());
 static BRACE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(.*?)\{([^{}]+)}(.*)$").unwrap());
 
@@ -88,9 +353,9 @@ static BRACE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(.*?)\{([^{}]+)}(.*
 struct Args {
     #[command(subcommand)]
     command: Option<Commands>,
-    #[arg(long, default_value = ".")]
+    #[arg(long, default_value = ".", env = "CODEBASE_DUMPER_PATH")]
     path: PathBuf,
-    #[arg(long = "type")]
+    #[arg(long = "type", env = "CODEBASE_DUMPER_TYPE")]
     file_type: Option<String>,
     #[arg(long)]
     clean: bool,
@@ -100,15 +365,22 @@ struct Args {
     no_clean_out: bool,
     #[arg(long)]
     progress: bool,
+    #[arg(long, short, action = clap::ArgAction::Count)]
+    verbose: u8,
     #[arg(long, short)]
-    verbose: bool,
+    quiet: bool,
     #[arg(long)]
     dry_run: bool,
-    #[arg(long, default_value_t = DEFAULT_LIMIT)]
+    #[arg(long, default_value_t = DEFAULT_LIMIT, env = "CODEBASE_DUMPER_LIMIT")]
     limit: usize,
     #[arg(long, default_value_t = MAX_FILE_SIZE)]
     max_file_size: u64,
-    #[arg(long, value_delimiter = ',', num_args = 1..)]
+    #[arg(
+        long,
+        value_delimiter = ',',
+        num_args = 1..,
+        env = "CODEBASE_DUMPER_EXCLUDE"
+    )]
     exclude: Vec<String>,
     #[arg(long, value_delimiter = ',', num_args = 1..)]
     include: Vec<String>,
@@ -124,6 +396,341 @@ struct Args {
     show_size: bool,
     #[arg(long)]
     no_config: bool,
+    #[arg(long, value_enum, default_value_t = SortKey::Path)]
+    sort: SortKey,
+    #[arg(long)]
+    reverse: bool,
+    #[arg(long)]
+    no_dumpignore: bool,
+    #[arg(long)]
+    toc: bool,
+    #[arg(long, short = 'y', alias = "no-wipe-confirm")]
+    yes: bool,
+    #[arg(long)]
+    exclude_generated: bool,
+    #[arg(long)]
+    generated_marker: Vec<String>,
+    #[arg(long)]
+    max_files: Option<usize>,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        num_args = 0..,
+        default_missing_values = DEFAULT_KEEP_MARKERS
+    )]
+    keep_comment_marker: Vec<String>,
+    #[arg(long)]
+    reproducible: bool,
+    #[arg(long)]
+    grep: Option<String>,
+    #[arg(long = "context", default_value_t = 0)]
+    grep_context: usize,
+    #[arg(long)]
+    part_headers: bool,
+    #[arg(long)]
+    respect_gitattributes: bool,
+    #[arg(long)]
+    trim_trailing: bool,
+    #[arg(long)]
+    tabs_to_spaces: Option<usize>,
+    #[arg(long)]
+    clean_cmd: Vec<String>,
+    #[arg(long)]
+    include_only: bool,
+    #[arg(long)]
+    git_info: bool,
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+    #[arg(long, default_value = DEFAULT_OUT_NAME)]
+    out_name: String,
+    #[arg(long)]
+    balance_chunks: bool,
+    #[arg(long)]
+    show_excluded: bool,
+    #[arg(long)]
+    minify_data: bool,
+    #[arg(long)]
+    skip_empty: bool,
+    #[arg(long)]
+    wrap_width: Option<usize>,
+    #[arg(long)]
+    diff_against: Option<PathBuf>,
+    #[arg(long)]
+    show_mtime: bool,
+    #[arg(long)]
+    language_stats: bool,
+    #[arg(long, default_value = DEFAULT_HEADER_TEMPLATE)]
+    file_header_template: String,
+    #[arg(long, default_value = "")]
+    file_footer_template: String,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+    #[arg(long)]
+    no_external_links: bool,
+    #[arg(long, value_enum, default_value_t = Encoding::Utf8)]
+    encoding: Encoding,
+    #[arg(long)]
+    progress_bytes: bool,
+    #[arg(long)]
+    summary_header: bool,
+    #[arg(long)]
+    output_extension: Option<String>,
+    #[arg(long)]
+    max_lines: Option<usize>,
+    #[arg(long)]
+    on_chunk: Option<String>,
+    #[arg(long, value_enum, default_value_t = LineEnding::Keep)]
+    line_ending: LineEnding,
+    #[arg(long)]
+    redact: bool,
+    #[arg(long)]
+    redact_pattern: Vec<String>,
+    #[arg(long)]
+    include_submodules: bool,
+    #[arg(long)]
+    mirror: Option<PathBuf>,
+    #[arg(long, value_enum)]
+    traversal: Option<Traversal>,
+    #[arg(long)]
+    tree_file: Option<PathBuf>,
+    #[arg(long)]
+    replace: Vec<String>,
+    #[arg(long)]
+    allow_output_in_source: bool,
+    #[arg(long, value_enum, default_value_t = TreeSort::Name)]
+    tree_sort: TreeSort,
+    #[arg(long)]
+    max_parts: Option<usize>,
+    #[arg(long, value_name = "MAX_BYTES", num_args = 0..=1, default_missing_value = BASE64_MAX_SIZE)]
+    binary_as_base64: Option<u64>,
+    #[arg(long)]
+    smart_excludes: bool,
+    #[arg(long)]
+    checksums: bool,
+    #[arg(long)]
+    context_file: Vec<PathBuf>,
+    #[arg(long, value_parser = parse_size)]
+    min_file_size: Option<u64>,
+    #[arg(long)]
+    interleave_tree: bool,
+    #[arg(long)]
+    report: Option<PathBuf>,
+    #[arg(long)]
+    no_canonicalize: bool,
+    #[arg(long)]
+    path_as_comment: bool,
+    #[arg(long, conflicts_with = "mirror")]
+    append: bool,
+    #[arg(long, value_name = "N")]
+    sample: Option<usize>,
+    #[arg(long, value_enum, default_value_t = SampleMode::Random)]
+    sample_mode: SampleMode,
+    #[arg(long)]
+    seed: Option<u64>,
+    #[arg(long)]
+    keep_empty_lines: bool,
+    #[arg(long)]
+    detect_language: bool,
+    #[arg(long)]
+    extensions: bool,
+    #[arg(long)]
+    comment_density: bool,
+    #[arg(long)]
+    chunk_by_dir: bool,
+    #[arg(long)]
+    strip_imports: bool,
+    #[arg(long, conflicts_with = "dry_run")]
+    check: bool,
+    #[arg(long, value_enum, default_value_t = DelimiterStyle::Template)]
+    delimiter_style: DelimiterStyle,
+    #[arg(long, default_value = "<<<")]
+    agent_sentinel: String,
+    #[arg(long, value_name = "WIDTH", num_args = 0..=1, default_missing_value = "0")]
+    pad_index: Option<usize>,
+    #[arg(long)]
+    timing: bool,
+    #[arg(long)]
+    skip_minified: bool,
+    #[arg(long, default_value_t = 400, value_name = "CHARS")]
+    minified_threshold: usize,
+    #[arg(long, conflicts_with = "mirror")]
+    clipboard: bool,
+    #[arg(long, default_value_t = MAX_CLEAN_SIZE, value_parser = parse_size)]
+    max_clean_size: u64,
+    #[arg(long, value_name = "FILE")]
+    include_file: Vec<PathBuf>,
+    #[arg(long, value_name = "FILE")]
+    exclude_file: Vec<PathBuf>,
+    #[arg(long, value_delimiter = ',', num_args = 1.., value_name = "NAME=EXT")]
+    treat_as: Vec<String>,
+    #[arg(long)]
+    allow_broad_scan: bool,
+    #[arg(long, value_name = "FILE")]
+    order_file: Option<PathBuf>,
+    #[arg(long)]
+    order_strict: bool,
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    overlap_files: usize,
+    #[arg(long)]
+    clean_diff: bool,
+    #[arg(long, value_name = "REGEX")]
+    fail_on_pattern: Vec<String>,
+    #[arg(long, value_name = "NAME", conflicts_with = "no_config")]
+    profile: Option<String>,
+    #[arg(long)]
+    no_ignore_files: bool,
+    #[arg(long, value_name = "DIR")]
+    relativize_to: Option<PathBuf>,
+    #[arg(long)]
+    outline: bool,
+    #[arg(long)]
+    follow_only_internal_links: bool,
+    #[arg(long, value_name = "TEXT")]
+    banner: Option<String>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    /// Relative path (default)
+    Path,
+    /// File name, ignoring directories
+    Name,
+    /// File size, smallest first
+    Size,
+    /// Extension, then path
+    Ext,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum TreeSort {
+    /// Byte order of the name, so `B` sorts before `a` (default)
+    Name,
+    /// Case-insensitive name order
+    NameCi,
+    /// Directories first, then files, each by name
+    Type,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Traversal {
+    /// Finish each directory, subdirectories included, before its next sibling
+    Depth,
+    /// All top-level files first, then each deeper level in turn
+    Breadth,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum SampleMode {
+    /// Uniformly random files (see `--seed`)
+    Random,
+    /// Round-robin across top-level directories
+    Representative,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum DelimiterStyle {
+    /// `--file-header-template` / `--file-footer-template` (default)
+    Template,
+    /// `<<<FILE path=.. bytes=..>>>` ... `<<<ENDFILE>>>`, for re-splitting by tools
+    Agent,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// Plain text with `--- FILE` delimiters (default)
+    Text,
+    /// One `<codebase>` document per part, files in CDATA sections
+    Xml,
+}
+
+impl OutputFormat {
+    /// Chunk file suffix used when the `--out` pattern was left at its default.
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Text => "txt",
+            OutputFormat::Xml => "xml",
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    /// UTF-8, written unchanged (default)
+    Utf8,
+    /// 7-bit ASCII, with other characters written as `\uXXXX` escapes
+    Ascii,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum LineEnding {
+    /// Each file keeps the line endings it was read with (default)
+    Keep,
+    /// Unix `\n` everywhere
+    Lf,
+    /// Windows `\r\n` everywhere, including headers and the tree
+    Crlf,
+}
+
+/// Comment syntax family of a source file, shared by `--clean` and `--path-as-comment`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum CommentStyle {
+    /// `//` and `/* */`; the fallback for unknown extensions unless `--detect-language`
+    C,
+    /// `#`
+    Script,
+    /// `#`, with Python's triple-quoted, prefixed and f-string literals
+    Python,
+    /// `//`, `/* */` and `#`
+    Php,
+    /// `--` and `/* */`
+    Sql,
+    /// `<!-- -->`
+    Html,
+}
+
+impl CommentStyle {
+    /// Style for a file, using `fallback` when the extension isn't recognised.
+    fn for_file(ext: &str, name: &str, fallback: CommentStyle) -> Self {
+        Self::known(ext, name).unwrap_or(fallback)
+    }
+
+    fn known(ext: &str, name: &str) -> Option<Self> {
+        Some(match ext {
+            "py" | "pyi" | "pyw" => CommentStyle::Python,
+            "rb" | "sh" | "yml" | "yaml" | "toml" | "env" => CommentStyle::Script,
+            "php" => CommentStyle::Php,
+            "sql" => CommentStyle::Sql,
+            "html" | "xml" | "svg" => CommentStyle::Html,
+            "rs" | "js" | "jsx" | "ts" | "tsx" | "mjs" | "go" | "c" | "h" | "cc" | "cpp"
+            | "hpp" | "cs" | "java" | "kt" | "scala" | "swift" | "dart" | "css" | "scss"
+            | "less" => CommentStyle::C,
+            // `--treat-as` may name these files' own pseudo-extensions.
+            "dockerfile" | "makefile" | "script" => CommentStyle::Script,
+            _ if name == "dockerfile" || name == "makefile" => CommentStyle::Script,
+            _ => return None,
+        })
+    }
+
+    fn regex(self) -> &'static Regex {
+        match self {
+            CommentStyle::C => &C_STYLE_REGEX,
+            CommentStyle::Script => &SCRIPT_STYLE_REGEX,
+            CommentStyle::Python => &PYTHON_STYLE_REGEX,
+            CommentStyle::Php => &PHP_STYLE_REGEX,
+            CommentStyle::Sql => &SQL_STYLE_REGEX,
+            CommentStyle::Html => &HTML_STYLE_REGEX,
+        }
+    }
+
+    /// Formats `text` as a single-line comment.
+    fn line_comment(self, text: &str) -> String {
+        match self {
+            CommentStyle::C | CommentStyle::Php => format!("// {}", text),
+            CommentStyle::Script | CommentStyle::Python => format!("# {}", text),
+            CommentStyle::Sql => format!("-- {}", text),
+            CommentStyle::Html => format!("<!-- {} -->", text),
+        }
+    }
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -141,8 +748,24 @@ enum Commands {
 }
 
 struct CompiledRules {
-    exclude_globs: Vec<Regex>,
-    include_globs: Vec<Regex>,
+    exclude_globs: Vec<(String, Regex)>,
+    /// `!pattern` excludes: re-include matching paths that a positive exclude caught.
+    negated_globs: Vec<(String, Regex)>,
+    include_globs: Vec<(String, Regex)>,
+    /// `!pattern` includes: paths that positive includes must not force in.
+    negated_include_globs: Vec<(String, Regex)>,
+    grep: Option<Regex>,
+    clean_cmds: HashMap<String, Vec<String>>,
+    redact: Vec<Regex>,
+    /// `--fail-on-pattern`: content that must never reach the output.
+    fail_on: Vec<Regex>,
+    /// Submodule roots (relative to the base) that exclude patterns must not hide.
+    submodules: Vec<PathBuf>,
+    replacements: Vec<(Regex, String)>,
+    /// Comment style for unrecognised extensions (see `--detect-language`).
+    comment_fallback: CommentStyle,
+    /// `--treat-as`: lowercased file names and the extension they stand for.
+    treat_as: Vec<(String, String)>,
 }
 
 #[derive(Debug, Clone)]
@@ -150,6 +773,36 @@ struct CollectedFile {
     path: PathBuf,
     display_path: PathBuf,
     size: u64,
+    /// Header annotation such as `modified` (from `--diff-against`).
+    tag: Option<&'static str>,
+    /// Named explicitly (`--include` or a file `--path`), so content filters don't drop it.
+    forced: bool,
+}
+
+/// One file's rendered block (header + content), ready to be placed into an output part.
+struct Section {
+    display_path: PathBuf,
+    text: String,
+    /// Lines of `text` above the file's delimiter line, for `--toc` line numbers.
+    header_line: usize,
+}
+
+impl Section {
+    fn new(display_path: PathBuf, text: String) -> Self {
+        let header_line = text.len() - text.trim_start_matches('\n').len();
+        Section {
+            display_path,
+            text,
+            header_line,
+        }
+    }
+}
+
+/// What earlier runs wrote into the output directory, read back for `--append`.
+#[derive(Default)]
+struct Manifest {
+    chunks: usize,
+    files: Vec<PathBuf>,
 }
 
 #[derive(Default)]
@@ -159,6 +812,24 @@ struct ProcessingStats {
     bytes_in: u64,
     bytes_out: u64,
     chunks: usize,
+    redactions: usize,
+    minified: usize,
+    /// Files skipped by `--max-lines`, with their line counts.
+    over_max_lines: Vec<(PathBuf, usize)>,
+    /// `--comment-density`: each cleaned file with its size before and after `--clean`.
+    cleaned: Vec<(PathBuf, u64, u64)>,
+    /// Stopped early by Ctrl-C; what was processed so far has been written.
+    interrupted: bool,
+    /// Every file written (chunks, or mirrored files) with its size in bytes.
+    outputs: Vec<(PathBuf, u64)>,
+    /// `--fail-on-pattern` hits: file, 1-based line, and the offending line.
+    forbidden: Vec<(PathBuf, usize, String)>,
+}
+
+#[derive(Default)]
+struct CollectStats {
+    unreadable_dirs: usize,
+    small_files: usize,
 }
 
 #[derive(Default)]
@@ -174,8 +845,7 @@ struct TreeContext<'a> {
     max_depth: usize,
     hidden: bool,
     show_size: bool,
-    visited: &'a mut HashSet<PathBuf>,
-    stats: &'a mut TreeStats,
+    sort: TreeSort,
 }
 
 // ============================================================================
@@ -183,11 +853,110 @@ struct TreeContext<'a> {
 // ============================================================================
 
 fn main() -> Result<()> {
+    let started = Instant::now();
+    ctrlc::set_handler(|| {
+        if !PROCESSING.load(AtomicOrdering::SeqCst)
+            || INTERRUPTED.swap(true, AtomicOrdering::SeqCst)
+        {
+            std::process::exit(130);
+        }
+    })
+    .context("Cannot install the Ctrl-C handler")?;
     let matches = Args::command().get_matches();
     let mut args = Args::from_arg_matches(&matches)?;
 
     if !args.no_config {
-        let _ = load_config_file(&mut args, &matches);
+        load_config_file(&mut args, &matches)?;
+    }
+
+    let dumpignore = args.path.join(".dumpignore");
+    if !args.no_dumpignore && !args.no_ignore_files && dumpignore.is_file() {
+        let patterns = load_patterns_from_file(&dumpignore)?;
+        args.exclude.extend(patterns);
+    }
+
+    if !args.no_ignore_files {
+        for name in IGNORE_FILES {
+            let file = args.path.join(name);
+            if file.is_file() {
+                let patterns = load_ignore_file(&file)?;
+                trace(
+                    &args,
+                    1,
+                    format_args!("{} patterns from {}", patterns.len(), name),
+                );
+                args.exclude.extend(patterns);
+            }
+        }
+    }
+
+    if args.smart_excludes {
+        for (marker, patterns) in SMART_EXCLUDES {
+            if args.path.join(marker).is_file() {
+                trace(
+                    &args,
+                    1,
+                    format_args!("smart excludes for {}: {}", marker, patterns.join(", ")),
+                );
+                for p in patterns.iter().map(|p| p.to_string()) {
+                    if !args.exclude.contains(&p) {
+                        args.exclude.push(p);
+                    }
+                }
+            }
+        }
+    }
+
+    for path in &args.exclude_file {
+        let patterns = load_patterns_from_file(path)?;
+        args.exclude.extend(patterns);
+    }
+    for path in &args.include_file {
+        let patterns = load_patterns_from_file(path)?;
+        args.include.extend(patterns);
+    }
+    // `!pattern` includes only cancel other includes, so they are kept apart from the
+    // positive ones (whose positions `collect_files` tracks).
+    let negated_includes: Vec<String> = args
+        .include
+        .iter()
+        .filter_map(|p| p.strip_prefix('!').map(str::to_string))
+        .collect();
+    args.include.retain(|p| !p.starts_with('!'));
+
+    let gitattributes = args.path.join(".gitattributes");
+    if args.respect_gitattributes && gitattributes.is_file() {
+        let patterns = load_linguist_excludes(&gitattributes)?;
+        args.exclude.extend(patterns);
+    }
+
+    // Separate directory/name options take precedence over the combined --out pattern.
+    let default_name = match &args.output_dir {
+        Some(_) => args.out_name == DEFAULT_OUT_NAME,
+        None => args.out == DEFAULT_OUT_PATTERN,
+    };
+    if let Some(dir) = &args.output_dir {
+        args.out = dir.join(&args.out_name).to_string_lossy().into_owned();
+    }
+
+    apply_output_extension(&mut args, default_name);
+
+    // `--type '*'` is spelled out for clarity but means the same as no type filter.
+    if args.file_type.as_deref() == Some("*") {
+        args.file_type = None;
+    }
+
+    if args.clean_diff {
+        args.clean = true;
+    }
+    // The density comes from what cleaning removed, so there is nothing to report without it.
+    if args.comment_density && !args.clean {
+        eprintln!("⚠️  --comment-density has no effect without --clean");
+    }
+
+    if args.reproducible {
+        args.sort = SortKey::Path;
+        args.reverse = false;
     }
 
     match &args.command {
@@ -196,37 +965,201 @@ fn main() -> Result<()> {
         None => {}
     }
 
-    let base_path = fs::canonicalize(&args.path).context("Source path not found")?;
+    let canonical_base = fs::canonicalize(&args.path).context("Source path not found")?;
+    if !args.allow_broad_scan {
+        check_broad_scan(&canonical_base)?;
+    }
+    if let Some(dir) = &args.relativize_to {
+        args.relativize_to = Some(fs::canonicalize(dir).context("--relativize-to path not found")?);
+    }
+    // --no-canonicalize keeps the path as typed for walking and display; safety checks
+    // still compare canonical paths.
+    let base_path = if args.no_canonicalize {
+        args.path.clone()
+    } else {
+        canonical_base.clone()
+    };
 
-    let rules = CompiledRules {
-        exclude_globs: expand_braces(&args.exclude)
-            .into_iter()
-            .flat_map(|s| glob_to_regex(&s))
-            .collect(),
-        include_globs: expand_braces(&args.include)
-            .into_iter()
-            .flat_map(|s| glob_to_regex(&s))
-            .collect(),
+    let mut rules = CompiledRules {
+        exclude_globs: compile_globs(
+            &args
+                .exclude
+                .iter()
+                .filter(|p| !p.starts_with('!'))
+                .cloned()
+                .collect::<Vec<_>>(),
+        ),
+        negated_globs: compile_globs(
+            &args
+                .exclude
+                .iter()
+                .filter_map(|p| p.strip_prefix('!').map(str::to_string))
+                .collect::<Vec<_>>(),
+        ),
+        include_globs: compile_globs(&args.include),
+        negated_include_globs: compile_globs(&negated_includes),
+        grep: args
+            .grep
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .context("Invalid --grep pattern")?,
+        clean_cmds: parse_clean_cmds(&args.clean_cmd)?,
+        redact: args
+            .redact_pattern
+            .iter()
+            .map(|p| Regex::new(p).with_context(|| format!("Invalid --redact-pattern {:?}", p)))
+            .collect::<Result<_>>()?,
+        fail_on: args
+            .fail_on_pattern
+            .iter()
+            .map(|p| Regex::new(p).with_context(|| format!("Invalid --fail-on-pattern {:?}", p)))
+            .collect::<Result<_>>()?,
+        replacements: parse_replacements(&args.replace)?,
+        submodules: if args.include_submodules {
+            load_submodule_paths(&base_path.join(".gitmodules"))?
+        } else {
+            Vec::new()
+        },
+        comment_fallback: CommentStyle::C,
+        treat_as: parse_treat_as(&args.treat_as)?,
     };
 
-    if !args.dry_run {
+    if args.extensions {
+        print_extension_report(&args, &base_path, &rules);
+        return Ok(());
+    }
+    if args.check {
+        return run_check(&args, &base_path, &canonical_base, &rules);
+    }
+
+    if let Some(dir) = &args.mirror {
+        // Mirroring into the source directory would overwrite the files being read.
+        if fs::canonicalize(dir).is_ok_and(|d| d == canonical_base) {
+            anyhow::bail!("--mirror {:?} is the source directory itself", dir);
+        }
+    } else if !args.dry_run {
         prepare_output_directory(&args)?;
     }
 
-    let (files, _) = collect_files(&args, &base_path, &rules)?;
+    let previous = if args.append {
+        read_manifest(&manifest_path(&args))?
+    } else {
+        Manifest::default()
+    };
 
-    if files.is_empty() {
-        println!("No files found to process.");
-        return Ok(());
+    record_time("setup", started);
+    let collect_started = Instant::now();
+    let (mut files, collect_stats) = collect_files(&args, &base_path, &rules)?;
+    if args.append {
+        let dumped: HashSet<&PathBuf> = previous.files.iter().collect();
+        files.retain(|cf| !dumped.contains(&cf.display_path));
     }
 
-    if args.dry_run {
-        println!("🔍 Dry run: Found {} files.", files.len());
-        return Ok(());
+    if let Some(other) = &args.diff_against {
+        let other = fs::canonicalize(other).context("--diff-against path not found")?;
+        files = diff_against(&args, files, &other, &rules)?;
+    }
+
+    if let Some(n) = args.sample {
+        let total = files.len();
+        files = sample_files(&args, files, n);
+        if files.len() < total {
+            info(
+                &args,
+                format_args!("🎲 Sampled {} of {} files.", files.len(), total),
+            );
+        }
     }
 
-    process_files(&args, &files, &base_path, &rules)?;
+    if let Some(order_file) = &args.order_file {
+        let order = load_patterns_from_file(order_file)?;
+        files = order_files(&args, files, &order);
+    }
+
+    if args.detect_language {
+        rules.comment_fallback = detect_comment_style(&files, &canonical_base, &rules);
+        info(
+            &args,
+            format_args!(
+                "🔎 Unrecognised extensions use {:?}-style comments.",
+                rules.comment_fallback
+            ),
+        );
+    }
+
+    record_time("collection", collect_started);
+
+    if args.language_stats && !files.is_empty() {
+        print_language_stats(&files);
+    }
+
+    let mut stats = ProcessingStats::default();
+    if files.is_empty() {
+        info(
+            &args,
+            format_args!(
+                "{}",
+                if args.append {
+                    "No new files since the last dump."
+                } else {
+                    "No files found to process."
+                }
+            ),
+        );
+    } else if args.dry_run {
+        info(
+            &args,
+            format_args!("🔍 Dry run: Found {} files.", files.len()),
+        );
+    } else {
+        if args.mirror.is_none() && !args.allow_output_in_source {
+            check_output_location(&args, &canonical_base)?;
+        }
+        stats = process_files(&args, &files, &base_path, &rules, &previous)?;
+        if args.clipboard && !stats.outputs.is_empty() {
+            if let Err(e) = copy_to_clipboard(&args, &stats.outputs) {
+                eprintln!("⚠️  Could not copy to the clipboard: {:#}", e);
+            }
+        }
+    }
 
+    if collect_stats.small_files > 0 {
+        info(
+            &args,
+            format_args!(
+                "Skipped {} file(s) smaller than --min-file-size.",
+                collect_stats.small_files
+            ),
+        );
+    }
+    if let Some(path) = &args.report {
+        write_report(
+            path,
+            &base_path,
+            files.len(),
+            &stats,
+            &collect_stats,
+            started,
+        )?;
+    }
+    if collect_stats.unreadable_dirs > 0 {
+        eprintln!(
+            "⚠️  {} director{} could not be read (permission denied); the dump is incomplete.",
+            collect_stats.unreadable_dirs,
+            if collect_stats.unreadable_dirs == 1 {
+                "y"
+            } else {
+                "ies"
+            }
+        );
+    }
+    if args.timing {
+        print_timings(started);
+    }
+    if stats.interrupted {
+        std::process::exit(130);
+    }
     Ok(())
 }
 
@@ -238,22 +1171,97 @@ fn collect_files(
     args: &Args,
     base_path: &Path,
     rules: &CompiledRules,
-) -> Result<(Vec<CollectedFile>, HashSet<usize>)> {
+) -> Result<(Vec<CollectedFile>, CollectStats)> {
     let mut files = Vec::new();
     let mut matched_indices = HashSet::new();
+    let mut collect_stats = CollectStats::default();
     let mut visited = HashSet::new();
     let target_ext = args
         .file_type
         .as_ref()
         .map(|s| s.trim_start_matches('.').to_lowercase());
 
+    // An explicitly named file is dumped as-is, whatever --type or the excludes say.
+    if base_path.is_file() {
+        trace(
+            args,
+            2,
+            format_args!("include {} (--path is a file)", base_path.display()),
+        );
+        files.push(CollectedFile {
+            path: base_path.to_path_buf(),
+            display_path: PathBuf::from(base_path.file_name().unwrap_or_default()),
+            size: fs::metadata(base_path).map(|m| m.len()).unwrap_or(0),
+            tag: None,
+            forced: true,
+        });
+        let canonical_base = fs::canonicalize(base_path)?;
+        collect_external_includes(
+            args,
+            rules,
+            &canonical_base,
+            &matched_indices,
+            &mut visited,
+            &mut files,
+            &mut collect_stats,
+        );
+        return Ok((files, collect_stats));
+    }
+
+    // The walk can take a while on big trees; a spinner shows it hasn't hung.
+    let spinner = (args.progress || args.progress_bytes).then(|| {
+        let s = ProgressBar::new_spinner();
+        s.set_style(
+            ProgressStyle::default_spinner()
+                .template("{spinner:.green} {msg}")
+                .unwrap(),
+        );
+        s.enable_steady_tick(std::time::Duration::from_millis(100));
+        s
+    });
+
+    let canonical_base = fs::canonicalize(base_path)?;
+    // Earlier dumps in the output directory must not be read back in as sources.
+    let out_dir = args.mirror.is_none().then(|| canonical_output_dir(args));
+    // With --allow-output-in-source the output directory is walked, so its artifacts (and a
+    // --report anywhere in the tree) are recognised by location instead.
+    let out_dir_in_walk = out_dir
+        .as_ref()
+        .and_then(|d| d.strip_prefix(&canonical_base).ok())
+        .map(|rel| base_path.join(rel));
+    let report = report_path(args);
+    let is_artifact = |e: &walkdir::DirEntry| {
+        let name = e.file_name().to_string_lossy();
+        (out_dir_in_walk.as_deref() == e.path().parent().map(Path::new)
+            && is_output_artifact(args, &name))
+            || report.as_ref().is_some_and(|r| {
+                r.file_name() == Some(e.file_name())
+                    && fs::canonicalize(e.path()).is_ok_and(|p| &p == r)
+            })
+    };
     let walker = WalkDir::new(base_path)
         .follow_links(true)
         .into_iter()
         .filter_entry(|e| {
+            // Prune a link escaping the tree before walkdir descends into its target.
+            if is_external_link(args, e, &canonical_base) {
+                let rel = e.path().strip_prefix(base_path).unwrap_or(e.path());
+                log_skip(args, true, rel, format_args!("symlink leaves the tree"));
+                return false;
+            }
+            if !e.file_type().is_dir() && is_artifact(e) {
+                let rel = e.path().strip_prefix(base_path).unwrap_or(e.path());
+                log_skip(args, true, rel, format_args!("dump output"));
+                return false;
+            }
             if e.file_type().is_dir() {
                 match fs::canonicalize(e.path()) {
                     Ok(c) => {
+                        if out_dir.as_ref() == Some(&c) && e.depth() > 0 {
+                            let rel = e.path().strip_prefix(base_path).unwrap_or(e.path());
+                            log_skip(args, true, rel, format_args!("output directory"));
+                            return false;
+                        }
                         if !visited.insert(c) {
                             return false;
                         }
@@ -261,50 +1269,175 @@ fn collect_files(
                     Err(_) => return false,
                 }
             }
-            !is_excluded(e.path(), base_path, rules, args.hidden)
+            match exclusion_reason(e.path(), base_path, rules, args.hidden) {
+                Some(reason) => {
+                    let rel = e.path().strip_prefix(base_path).unwrap_or(e.path());
+                    // Directories may hide candidates, so --show-excluded reports them too.
+                    let candidate = e.file_type().is_dir()
+                        || (!args.include_only
+                            && matches_type(e.path(), target_ext.as_deref(), rules))
+                        || rules
+                            .include_globs
+                            .iter()
+                            .any(|(_, re)| re.is_match(&rel.to_string_lossy()));
+                    log_skip(args, candidate, rel, format_args!("{}", reason));
+                    false
+                }
+                None => true,
+            }
         });
 
-    for entry in walker.filter_map(|e| e.ok()) {
+    for entry in walker {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(err) => {
+                if err.io_error().map(|e| e.kind()) == Some(ErrorKind::PermissionDenied) {
+                    collect_stats.unreadable_dirs += 1;
+                    if let Some(p) = err.path() {
+                        log_skip(args, true, p, format_args!("permission denied"));
+                    }
+                }
+                continue;
+            }
+        };
+        if let Some(s) = &spinner {
+            let dir = entry.path().parent().unwrap_or(base_path);
+            s.set_message(format!(
+                "Collecting: {} files — {}",
+                files.len(),
+                dir.strip_prefix(base_path).unwrap_or(dir).display()
+            ));
+        }
         if !entry.file_type().is_file() {
             continue;
         }
 
         let path = entry.path();
         let rel_path = path.strip_prefix(base_path).unwrap_or(path);
-        let mut should_add = false;
+        let mut reason = None;
+        let mut forced = false;
 
-        if let Some(ref target) = target_ext {
-            if path
-                .extension()
-                .map(|e| e.to_string_lossy().to_lowercase() == *target)
-                .unwrap_or(false)
-            {
-                should_add = true;
-            }
-        } else {
-            should_add = true;
+        // Under --include-only the extension filter is ignored; only includes can add files.
+        if !args.include_only && matches_type(path, target_ext.as_deref(), rules) {
+            reason = Some(match target_ext {
+                Some(ref target) => format!("matched extension `{}`", target),
+                None => "no type filter".to_string(),
+            });
         }
 
-        for (i, re) in rules.include_globs.iter().enumerate() {
-            if re.is_match(&rel_path.to_string_lossy()) {
-                should_add = true;
+        for (i, (pat, re)) in rules.include_globs.iter().enumerate() {
+            if re.is_match(&rel_path.to_string_lossy()) && !is_include_negated(rel_path, rules) {
+                reason = Some(format!("matched include pattern `{}`", pat));
                 matched_indices.insert(i);
+                forced = true;
             }
         }
 
-        if should_add {
+        match &reason {
+            Some(r) => trace(
+                args,
+                2,
+                format_args!("include {} ({})", rel_path.display(), r),
+            ),
+            None => trace(
+                args,
+                2,
+                format_args!(
+                    "skip    {} (no --type or --include match)",
+                    rel_path.display()
+                ),
+            ),
+        }
+
+        if reason.is_some() {
+            // Symlinks are followed, so a file inside the tree may resolve to one outside it.
+            if let Ok(target) = fs::canonicalize(path) {
+                if !target.starts_with(&canonical_base) {
+                    if args.no_external_links {
+                        log_skip(
+                            args,
+                            true,
+                            rel_path,
+                            format_args!("external symlink target: {}", target.display()),
+                        );
+                        continue;
+                    }
+                    eprintln!(
+                        "⚠️  external symlink target: {} -> {}",
+                        rel_path.display(),
+                        target.display()
+                    );
+                }
+            }
             let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+            if !forced && args.min_file_size.is_some_and(|min| size < min) {
+                collect_stats.small_files += 1;
+                log_skip(
+                    args,
+                    true,
+                    rel_path,
+                    format_args!("smaller than --min-file-size"),
+                );
+                continue;
+            }
             files.push(CollectedFile {
                 path: path.to_path_buf(),
                 display_path: rel_path.to_path_buf(),
                 size,
+                tag: None,
+                forced,
             });
+            if reached_max_files(args, &files) {
+                break;
+            }
         }
     }
 
+    collect_external_includes(
+        args,
+        rules,
+        &canonical_base,
+        &matched_indices,
+        &mut visited,
+        &mut files,
+        &mut collect_stats,
+    );
+
+    if let Some(s) = spinner {
+        s.finish_and_clear();
+    }
+    sort_files(&mut files, args.sort, args.reverse, args.traversal);
+    Ok((files, collect_stats))
+}
+
+/// `--include` paths outside `--path`: a file is added as `[external]/name`, a directory
+/// becomes an extra walk root with the same type and exclude rules. Includes already
+/// matched inside the tree (`matched`) are skipped.
+fn collect_external_includes(
+    args: &Args,
+    rules: &CompiledRules,
+    canonical_base: &Path,
+    matched: &HashSet<usize>,
+    visited: &mut HashSet<PathBuf>,
+    files: &mut Vec<CollectedFile>,
+    collect_stats: &mut CollectStats,
+) {
+    let target_ext = args
+        .file_type
+        .as_ref()
+        .map(|s| s.trim_start_matches('.').to_lowercase());
     for (i, inc) in args.include.iter().enumerate() {
+        // Once --max-files is hit no further roots are walked.
+        if args.max_files.is_some_and(|max| files.len() >= max) {
+            break;
+        }
         let p = Path::new(inc);
-        if p.is_file() && !matched_indices.contains(&i) {
+        if p.is_file() && !matched.contains(&i) {
+            trace(
+                args,
+                2,
+                format_args!("include {} (external include)", p.display()),
+            );
             let size = fs::metadata(p).map(|m| m.len()).unwrap_or(0);
             files.push(CollectedFile {
                 path: p.to_path_buf(),
@@ -313,20 +1446,420 @@ fn collect_files(
                     p.file_name().unwrap_or_default().to_string_lossy()
                 )),
                 size,
+                tag: None,
+                forced: true,
             });
-            matched_indices.insert(i);
+            reached_max_files(args, files);
+        } else if p.is_dir() {
+            // A directory outside the source tree becomes an extra walk root with the same
+            // type and exclude rules; one inside it was already walked.
+            let Ok(root) = fs::canonicalize(p) else {
+                continue;
+            };
+            if root.starts_with(canonical_base) || !visited.insert(root.clone()) {
+                continue;
+            }
+            let name = root.file_name().unwrap_or_default().to_os_string();
+            let walker = WalkDir::new(&root)
+                .follow_links(true)
+                .into_iter()
+                .filter_entry(|e| {
+                    if is_external_link(args, e, &root) {
+                        let rel = e.path().strip_prefix(&root).unwrap_or(e.path());
+                        log_skip(args, true, rel, format_args!("symlink leaves the tree"));
+                        return false;
+                    }
+                    !is_excluded(e.path(), &root, rules, args.hidden)
+                });
+            for entry in walker.filter_map(|e| e.ok()) {
+                let path = entry.path();
+                if !entry.file_type().is_file()
+                    || !(args.include_only || matches_type(path, target_ext.as_deref(), rules))
+                {
+                    continue;
+                }
+                let display_path = Path::new("[external]")
+                    .join(&name)
+                    .join(path.strip_prefix(&root).unwrap_or(path));
+                let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                if args.min_file_size.is_some_and(|min| size < min) {
+                    collect_stats.small_files += 1;
+                    log_skip(
+                        args,
+                        true,
+                        &display_path,
+                        format_args!("smaller than --min-file-size"),
+                    );
+                    continue;
+                }
+                trace(
+                    args,
+                    2,
+                    format_args!("include {} (external directory)", path.display()),
+                );
+                files.push(CollectedFile {
+                    path: path.to_path_buf(),
+                    display_path,
+                    size,
+                    tag: None,
+                    forced: false,
+                });
+                if reached_max_files(args, files) {
+                    break;
+                }
+            }
         }
     }
+}
+
+/// Whether `files` has reached `--max-files`; warns that the results are truncated if so.
+fn reached_max_files(args: &Args, files: &[CollectedFile]) -> bool {
+    let reached = args.max_files.is_some_and(|max| files.len() >= max);
+    if reached {
+        eprintln!(
+            "⚠️  Reached --max-files limit ({}); results are truncated.",
+            files.len()
+        );
+    }
+    reached
+}
+
+/// Keeps only files that are new or changed relative to the same relative path under
+/// `other`, tagging them `new`/`modified`, and prints a summary that also counts files
+/// present only in `other` (removed here).
+fn diff_against(
+    args: &Args,
+    files: Vec<CollectedFile>,
+    other: &Path,
+    rules: &CompiledRules,
+) -> Result<Vec<CollectedFile>> {
+    let target_ext = args
+        .file_type
+        .as_ref()
+        .map(|s| s.trim_start_matches('.').to_lowercase());
+    let ours: HashSet<PathBuf> = files.iter().map(|f| f.display_path.clone()).collect();
+    let removed = WalkDir::new(other)
+        .into_iter()
+        .filter_entry(|e| !is_excluded(e.path(), other, rules, args.hidden))
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && matches_type(e.path(), target_ext.as_deref(), rules))
+        .filter(|e| !ours.contains(e.path().strip_prefix(other).unwrap_or(e.path())))
+        .count();
+
+    let (mut added, mut changed, mut unchanged) = (0, 0, 0);
+    let mut kept = Vec::new();
+    for mut cf in files {
+        // External includes have no counterpart to compare against.
+        if cf.display_path.starts_with("[external]") {
+            kept.push(cf);
+            continue;
+        }
+        let counterpart = other.join(&cf.display_path);
+        if !counterpart.is_file() {
+            cf.tag = Some("new");
+            added += 1;
+        } else if fs::read(&cf.path).ok() != fs::read(&counterpart).ok() {
+            cf.tag = Some("modified");
+            changed += 1;
+        } else {
+            unchanged += 1;
+            continue;
+        }
+        kept.push(cf);
+    }
+    info(
+        args,
+        format_args!(
+            "🔀 Diff against {:?}: {} new, {} modified, {} removed ({} unchanged skipped).",
+            other, added, changed, removed, unchanged
+        ),
+    );
+    Ok(kept)
+}
+
+fn language_for(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    LANGUAGES
+        .iter()
+        .find(|(e, _)| *e == ext)
+        .map(|(_, lang)| *lang)
+        .unwrap_or("Other")
+}
 
-    files.sort_by(|a, b| a.display_path.cmp(&b.display_path));
-    Ok((files, matched_indices))
+/// The comment style of most collected bytes in recognised files, else the root manifest's,
+/// else C.
+fn detect_comment_style(
+    files: &[CollectedFile],
+    base: &Path,
+    rules: &CompiledRules,
+) -> CommentStyle {
+    let mut bytes: HashMap<CommentStyle, u64> = HashMap::new();
+    for f in files {
+        let ext = file_ext(&f.display_path, rules);
+        let name = f
+            .display_path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if let Some(style) = CommentStyle::known(&ext, &name) {
+            *bytes.entry(style).or_default() += f.size;
+        }
+    }
+    // Config formats (`toml`, `yml`) count as Script, so only trust a clear majority.
+    let total: u64 = bytes.values().sum();
+    if let Some((&style, &size)) = bytes.iter().max_by_key(|(_, size)| **size) {
+        if size * 2 > total {
+            return style;
+        }
+    }
+    PROJECT_MANIFESTS
+        .iter()
+        .find(|(marker, _)| base.join(marker).is_file())
+        .map(|(_, style)| *style)
+        .unwrap_or(CommentStyle::C)
+}
+
+/// `--extensions`: tallies every file under `base` that survives the excludes by extension,
+/// ignoring `--type`, so users can see what a tree holds before choosing one.
+fn print_extension_report(args: &Args, base: &Path, rules: &CompiledRules) {
+    let mut tally: HashMap<String, (usize, u64)> = HashMap::new();
+    for entry in WalkDir::new(base)
+        .into_iter()
+        .filter_entry(|e| !is_excluded(e.path(), base, rules, args.hidden))
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+    {
+        let ext = entry
+            .path()
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_else(|| "(none)".to_string());
+        let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+        let slot = tally.entry(ext).or_default();
+        slot.0 += 1;
+        slot.1 += size;
+    }
+    let mut rows: Vec<_> = tally.into_iter().collect();
+    rows.sort_by(|a, b| {
+        b.1 .0
+            .cmp(&a.1 .0)
+            .then(b.1 .1.cmp(&a.1 .1))
+            .then(a.0.cmp(&b.0))
+    });
+    println!("📂 Extensions by file count:");
+    for (ext, (count, size)) in rows {
+        println!("   {:12} {:>6} files  ({})", ext, count, format_size(size));
+    }
+}
+
+/// Prints the share of collected bytes per language, largest first.
+fn print_language_stats(files: &[CollectedFile]) {
+    let mut bytes: HashMap<&str, u64> = HashMap::new();
+    for f in files {
+        *bytes.entry(language_for(&f.display_path)).or_default() += f.size;
+    }
+    let total = bytes.values().sum::<u64>().max(1);
+    let mut langs: Vec<_> = bytes.into_iter().collect();
+    langs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    println!("📊 Languages by size:");
+    for (lang, size) in langs {
+        println!(
+            "   {:12} {:>5.1}%  ({})",
+            lang,
+            size as f64 * 100.0 / total as f64,
+            format_size(size)
+        );
+    }
+}
+
+/// Orders files by `key`, falling back to the relative path so ties stay deterministic.
+/// `reverse` flips the whole comparator, e.g. `--sort size --reverse` puts the largest first.
+/// With a `traversal`, files are first grouped by directory and `key`/`reverse` only order
+/// files within the same directory.
+fn sort_files(
+    files: &mut [CollectedFile],
+    key: SortKey,
+    reverse: bool,
+    traversal: Option<Traversal>,
+) {
+    let ext = |f: &CollectedFile| {
+        f.display_path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default()
+    };
+    // Component-wise comparison sorts a directory before everything beneath it.
+    let dir = |f: &CollectedFile| f.display_path.parent().map(Path::to_path_buf);
+    let depth = |f: &CollectedFile| f.display_path.components().count();
+    files.sort_by(|a, b| {
+        let group = match traversal {
+            None => Ordering::Equal,
+            Some(Traversal::Depth) => dir(a).cmp(&dir(b)),
+            Some(Traversal::Breadth) => depth(a).cmp(&depth(b)).then_with(|| dir(a).cmp(&dir(b))),
+        };
+        if group != Ordering::Equal {
+            return group;
+        }
+        let ord = match key {
+            SortKey::Path => a.display_path.cmp(&b.display_path),
+            SortKey::Name => a.display_path.file_name().cmp(&b.display_path.file_name()),
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Ext => ext(a).cmp(&ext(b)),
+        }
+        .then_with(|| a.display_path.cmp(&b.display_path));
+        if reverse {
+            ord.reverse()
+        } else {
+            ord
+        }
+    });
+}
+
+/// Picks `n` of `files` for `--sample`, keeping the picked files in their sorted order.
+fn sample_files(args: &Args, files: Vec<CollectedFile>, n: usize) -> Vec<CollectedFile> {
+    if files.len() <= n {
+        return files;
+    }
+    let mut picked: Vec<usize> = match args.sample_mode {
+        SampleMode::Random => {
+            // --reproducible must not depend on the clock, so it falls back to a fixed seed.
+            let mut state = args.seed.unwrap_or_else(|| {
+                if args.reproducible {
+                    0
+                } else {
+                    std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_nanos() as u64)
+                        .unwrap_or_default()
+                }
+            });
+            // Partial Fisher-Yates: the first `n` slots end up a uniform sample.
+            let mut indices: Vec<usize> = (0..files.len()).collect();
+            for i in 0..n {
+                let j = i + (splitmix64(&mut state) % (files.len() - i) as u64) as usize;
+                indices.swap(i, j);
+            }
+            indices.truncate(n);
+            indices
+        }
+        SampleMode::Representative => {
+            // Files directly under the root form a group of their own.
+            let mut groups: Vec<(PathBuf, Vec<usize>)> = Vec::new();
+            for (i, f) in files.iter().enumerate() {
+                let mut components = f.display_path.components();
+                let top = match (components.next(), components.next()) {
+                    (Some(c), Some(_)) => PathBuf::from(c.as_os_str()),
+                    _ => PathBuf::new(),
+                };
+                match groups.iter_mut().find(|(g, _)| *g == top) {
+                    Some((_, members)) => members.push(i),
+                    None => groups.push((top, vec![i])),
+                }
+            }
+            let mut picked = Vec::with_capacity(n);
+            let mut round = 0;
+            while picked.len() < n {
+                for (_, members) in &groups {
+                    if let Some(&i) = members.get(round) {
+                        if picked.len() < n {
+                            picked.push(i);
+                        }
+                    }
+                }
+                round += 1;
+            }
+            picked
+        }
+    };
+    picked.sort_unstable();
+    let mut files: Vec<Option<CollectedFile>> = files.into_iter().map(Some).collect();
+    picked.iter().filter_map(|&i| files[i].take()).collect()
+}
+
+/// Moves the files listed in `--order-file` to the front, in list order. Unlisted files keep
+/// their `--sort` order after them, or are dropped with `--order-strict`.
+fn order_files(args: &Args, files: Vec<CollectedFile>, order: &[String]) -> Vec<CollectedFile> {
+    let key = |p: &str| p.trim_start_matches("./").replace('\\', "/");
+    let rank: HashMap<String, usize> = order
+        .iter()
+        .enumerate()
+        .rev()
+        .map(|(i, p)| (key(p), i))
+        .collect();
+    let mut listed: Vec<(usize, CollectedFile)> = Vec::new();
+    let mut rest = Vec::new();
+    for f in files {
+        match rank.get(&key(&f.display_path.to_string_lossy())) {
+            Some(&i) => listed.push((i, f)),
+            None => rest.push(f),
+        }
+    }
+    listed.sort_by_key(|(i, _)| *i);
+    if listed.len() < rank.len() {
+        eprintln!(
+            "⚠️  {} path(s) in --order-file were not collected.",
+            rank.len() - listed.len()
+        );
+    }
+    let dropped = if args.order_strict { rest.len() } else { 0 };
+    if dropped > 0 {
+        info(
+            args,
+            format_args!("📋 --order-strict dropped {} unlisted files.", dropped),
+        );
+    }
+    let mut ordered: Vec<CollectedFile> = listed.into_iter().map(|(_, f)| f).collect();
+    if !args.order_strict {
+        ordered.extend(rest);
+    }
+    ordered
+}
+
+/// SplitMix64: a small, well-mixed PRNG, so a `--seed` gives the same sample everywhere.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
 }
 
 fn is_excluded(path: &Path, base: &Path, rules: &CompiledRules, include_hidden: bool) -> bool {
-    let rel_path = path.strip_prefix(base).unwrap_or(path).to_string_lossy();
-    for re in &rules.exclude_globs {
+    exclusion_reason(path, base, rules, include_hidden).is_some()
+}
+
+/// `--follow-only-internal-links`: whether `e` is a symlink resolving outside the canonical
+/// walk root (or not resolving at all).
+fn is_external_link(args: &Args, e: &walkdir::DirEntry, root: &Path) -> bool {
+    args.follow_only_internal_links
+        && e.path_is_symlink()
+        && !fs::canonicalize(e.path()).is_ok_and(|target| target.starts_with(root))
+}
+
+/// Returns why `path` is excluded from the walk, or `None` if it should be kept.
+fn exclusion_reason(
+    path: &Path,
+    base: &Path,
+    rules: &CompiledRules,
+    include_hidden: bool,
+) -> Option<String> {
+    let rel = path.strip_prefix(base).unwrap_or(path);
+    let rel_path = rel.to_string_lossy();
+    // Submodules, and the directories leading to them, bypass exclude patterns; siblings
+    // inside an excluded parent are still matched by the pattern themselves.
+    let in_submodule = rules
+        .submodules
+        .iter()
+        .any(|m| rel.starts_with(m) || (path.is_dir() && m.starts_with(rel)));
+    for (pat, re) in rules.exclude_globs.iter().filter(|_| !in_submodule) {
         if re.is_match(&rel_path) {
-            return true;
+            if is_reincluded(path, &rel_path, rules) {
+                break;
+            }
+            return Some(format!("excluded by pattern `{}`", pat));
         }
     }
 
@@ -336,10 +1869,50 @@ fn is_excluded(path: &Path, base: &Path, rules: &CompiledRules, include_hidden:
             .map(|n| n.to_string_lossy())
             .unwrap_or_default();
         if name.starts_with('.') && path != base {
-            return true;
+            return Some("hidden".to_string());
         }
     }
-    false
+    None
+}
+
+/// Whether a `!pattern` exclude or a (non-negated) include overrides a positive exclude for
+/// this path. An excluded directory is kept (not pruned) when one of them names a path inside
+/// it, e.g. `!tests/fixtures/a.json` under an excluded `tests`; its other contents are still
+/// excluded one by one. Wildcard-only patterns like `*.md` never reopen a directory.
+fn is_reincluded(path: &Path, rel_path: &str, rules: &CompiledRules) -> bool {
+    let included = rules
+        .include_globs
+        .iter()
+        .any(|(_, re)| re.is_match(rel_path))
+        && !is_include_negated(Path::new(rel_path), rules);
+    if included
+        || rules
+            .negated_globs
+            .iter()
+            .any(|(_, re)| re.is_match(rel_path))
+    {
+        return true;
+    }
+    if !path.is_dir() {
+        return false;
+    }
+    let dir = format!("{}/", rel_path.replace('\\', "/"));
+    rules
+        .negated_globs
+        .iter()
+        .chain(&rules.include_globs)
+        .any(|(pat, _)| {
+            let literal = pat.split(['*', '?']).next().unwrap_or_default();
+            literal.trim_start_matches('/').starts_with(&dir)
+        })
+}
+
+fn is_include_negated(rel_path: &Path, rules: &CompiledRules) -> bool {
+    let rel = rel_path.to_string_lossy();
+    rules
+        .negated_include_globs
+        .iter()
+        .any(|(_, re)| re.is_match(&rel))
 }
 
 fn process_files(
@@ -347,14 +1920,34 @@ fn process_files(
     files: &[CollectedFile],
     base: &Path,
     rules: &CompiledRules,
-) -> Result<()> {
-    let mut buffer = String::with_capacity(args.limit);
+    previous: &Manifest,
+) -> Result<ProcessingStats> {
     let mut stats = ProcessingStats {
         files_total: files.len(),
         ..Default::default()
     };
+    let mut sections = if args.mirror.is_none() && can_stream(args) {
+        SectionSink::Stream(Box::new(ChunkStream::new(
+            args, base, rules, previous, &mut stats,
+        )?))
+    } else {
+        SectionSink::Buffer(Vec::new())
+    };
+    let mut held = Vec::new();
 
-    let pb = if args.progress {
+    let pb = if args.progress_bytes {
+        // Sizing by bytes keeps the bar and ETA steady when file sizes vary widely.
+        let p = ProgressBar::new(files.iter().map(|cf| cf.size).sum());
+        p.set_style(
+            ProgressStyle::default_bar()
+                .template(
+                    "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})",
+                )
+                .unwrap()
+                .progress_chars("#>-"),
+        );
+        Some(p)
+    } else if args.progress {
         let p = ProgressBar::new(files.len() as u64);
         p.set_style(
             ProgressStyle::default_bar()
@@ -369,73 +1962,1439 @@ fn process_files(
         None
     };
 
-    if !args.no_tree {
-        buffer.push_str(&generate_tree(args, base, rules));
-    }
-
-    let type_label = args.file_type.as_deref().unwrap_or("all");
-
+    PROCESSING.store(true, AtomicOrdering::SeqCst);
+    let loop_started = Instant::now();
+    let written_before = phase_time("writing");
     for cf in files {
+        if INTERRUPTED.load(AtomicOrdering::SeqCst) {
+            stats.interrupted = true;
+            break;
+        }
         if let Some(ref p) = pb {
-            p.inc(1);
+            p.inc(if args.progress_bytes { cf.size } else { 1 });
         }
-        if cf.size > args.max_file_size || !is_likely_text(&cf.path) {
+        if cf.size > args.max_file_size {
+            log_skip(
+                args,
+                true,
+                &cf.display_path,
+                format_args!("larger than {}", format_size(args.max_file_size)),
+            );
+            continue;
+        }
+        if !is_likely_text(&cf.path) {
+            if let Some(section) = base64_section(args, cf) {
+                stats.bytes_in += cf.size;
+                stats.files_processed += 1;
+                sections.push(section, &mut stats)?;
+                continue;
+            }
+            log_skip(args, true, &cf.display_path, format_args!("binary"));
             continue;
         }
 
+        if args.exclude_generated {
+            if let Some(marker) = find_generated_marker(&cf.path, &args.generated_marker) {
+                log_skip(
+                    args,
+                    true,
+                    &cf.display_path,
+                    format_args!("generated: `{}`", marker),
+                );
+                continue;
+            }
+        }
+
         let content = match fs::read_to_string(&cf.path) {
             Ok(s) => s,
             Err(_) => {
-                if args.verbose {
-                    println!("⚠️  Skipping non-UTF8: {:?}", cf.display_path);
+                if let Some(section) = base64_section(args, cf) {
+                    stats.bytes_in += cf.size;
+                    stats.files_processed += 1;
+                    sections.push(section, &mut stats)?;
+                    continue;
                 }
+                log_skip(args, true, &cf.display_path, format_args!("non-UTF8"));
                 continue;
             }
         };
 
+        if args.skip_minified && !cf.forced {
+            // Bundles are a few enormous lines; hand-written code averages well under 100.
+            let avg = content.len() / content.lines().count().max(1);
+            if avg > args.minified_threshold {
+                log_skip(
+                    args,
+                    true,
+                    &cf.display_path,
+                    format_args!("minified: {} chars/line on average", avg),
+                );
+                stats.minified += 1;
+                continue;
+            }
+        }
+
         stats.bytes_in += content.len() as u64;
-        let processed = if args.clean {
-            clean_content(&cf.path, &content)
+        // Processing assumes `\n`; stray `\r`s would defeat blank-line collapsing and cleaning.
+        let crlf = content.contains("\r\n");
+        let content = if crlf {
+            content.replace("\r\n", "\n")
         } else {
             content
         };
+        let ext = file_ext(&cf.path, rules);
+        let external = rules.clean_cmds.get(&ext).and_then(|cmd| {
+            run_clean_cmd(cmd, &content)
+                .map_err(|e| {
+                    eprintln!(
+                        "⚠️  --clean-cmd failed for {}: {:#}; using built-in processing",
+                        cf.display_path.display(),
+                        e
+                    )
+                })
+                .ok()
+        });
+        let minified = match external {
+            Some(_) => None,
+            None if args.minify_data => minify_data(&ext, &content).map(|r| {
+                r.unwrap_or_else(|e| {
+                    eprintln!(
+                        "⚠️  Cannot minify {}: {}; keeping raw content",
+                        cf.display_path.display(),
+                        e
+                    );
+                    content.clone()
+                })
+            }),
+            None => None,
+        };
+        let processed = match (external, minified) {
+            (Some(out), _) | (None, Some(out)) => out,
+            (None, None)
+                if args.clean
+                    && args.max_clean_size > 0
+                    && content.len() as u64 > args.max_clean_size =>
+            {
+                eprintln!(
+                    "⚠️  {} is {}, over --max-clean-size; emitting it without cleaning",
+                    cf.display_path.display(),
+                    format_size(content.len() as u64)
+                );
+                content
+            }
+            (None, None) if args.clean => {
+                let cleaned = clean_content(args, &cf.path, &content, rules);
+                if args.comment_density {
+                    stats.cleaned.push((
+                        cf.display_path.clone(),
+                        content.len() as u64,
+                        cleaned.len() as u64,
+                    ));
+                }
+                if args.clean_diff {
+                    clean_diff(&cf.display_path, &content, &cleaned)
+                } else {
+                    cleaned
+                }
+            }
+            (None, None) => content,
+        };
+
+        // A --clean-diff must stay a diff of what cleaning removes, so these leave it alone.
+        let processed = if args.outline && !args.clean_diff {
+            outline(&ext, &processed).unwrap_or(processed)
+        } else {
+            processed
+        };
+
+        let processed = if args.strip_imports && !args.clean_diff {
+            strip_imports(&cf.path, processed, rules)
+        } else {
+            processed
+        };
+
+        // Redact before --grep and the header are applied, so no later step can echo a secret.
+        let processed = if args.redact || !rules.redact.is_empty() {
+            let (text, n) = redact_secrets(&processed, args.redact, &rules.redact);
+            stats.redactions += n;
+            text
+        } else {
+            processed
+        };
+
+        let processed = rules
+            .replacements
+            .iter()
+            .fold(processed, |text, (re, with)| {
+                re.replace_all(&text, with.as_str()).into_owned()
+            });
+
+        if let Some(max) = args.max_lines {
+            let lines = processed.lines().count();
+            if lines > max && !cf.forced {
+                log_skip(
+                    args,
+                    true,
+                    &cf.display_path,
+                    format_args!("{} lines, over --max-lines {}", lines, max),
+                );
+                stats.over_max_lines.push((cf.display_path.clone(), lines));
+                continue;
+            }
+        }
+
+        let processed = normalize_whitespace(args, processed);
+        let processed = match args.wrap_width {
+            Some(width) if width > 0 => wrap_lines(&processed, width),
+            _ => processed,
+        };
+
+        let processed = match &rules.grep {
+            Some(re) => match grep_lines(&processed, re, args.grep_context) {
+                Some(hunks) => hunks,
+                None => {
+                    trace(
+                        args,
+                        2,
+                        format_args!("skip    {} (no --grep match)", cf.display_path.display()),
+                    );
+                    continue;
+                }
+            },
+            None => processed,
+        };
+
+        if args.skip_empty && processed.trim().is_empty() {
+            log_skip(args, true, &cf.display_path, format_args!("empty"));
+            continue;
+        }
+
+        if !rules.fail_on.is_empty() {
+            let hits = forbidden_lines(&processed, &rules.fail_on);
+            if !hits.is_empty() {
+                stats.forbidden.extend(
+                    hits.into_iter()
+                        .map(|(n, l)| (cf.display_path.clone(), n, l)),
+                );
+                continue;
+            }
+            if !stats.forbidden.is_empty() {
+                // The run will abort; keep scanning for the report but stop producing output.
+                continue;
+            }
+        }
+
+        let processed = match path_comment(args, &cf.display_path, rules) {
+            Some(comment) => format!("{}\n{}", comment, processed),
+            None => processed,
+        };
+
+        let processed = if crlf && args.line_ending == LineEnding::Keep {
+            processed.replace('\n', "\r\n")
+        } else {
+            processed
+        };
+
+        stats.files_processed += 1;
+        if let Some(dir) = &args.mirror {
+            let out = encode_output(args, processed);
+            let path = dir.join(&cf.display_path);
+            if rules.fail_on.is_empty() {
+                stats.bytes_out += out.len() as u64;
+                write_atomic(&path, &out)?;
+                stats.outputs.push((path, out.len() as u64));
+            } else {
+                // Nothing may be written until every file has passed --fail-on-pattern.
+                held.push((path, out));
+            }
+            continue;
+        }
+        sections.push(
+            Section::new(
+                cf.display_path.clone(),
+                render_section(args, cf, &processed),
+            ),
+            &mut stats,
+        )?;
+    }
+
+    // Streamed chunks are written inside the loop; that time belongs to "writing".
+    let written = phase_time("writing") - written_before;
+    add_time("processing", loop_started.elapsed().saturating_sub(written));
+    if let Some(ref p) = pb {
+        p.finish_and_clear();
+    }
+    if !stats.forbidden.is_empty() {
+        for (path, line, text) in &stats.forbidden {
+            eprintln!("❌ {}:{}: {}", path.display(), line, text);
+        }
+        anyhow::bail!(
+            "--fail-on-pattern matched {} line(s); nothing was written",
+            stats.forbidden.len()
+        );
+    }
+    for (path, out) in held {
+        stats.bytes_out += out.len() as u64;
+        write_atomic(&path, &out)?;
+        stats.outputs.push((path, out.len() as u64));
+    }
+    if let SectionSink::Buffer(sections) = &mut sections {
+        if args.interleave_tree {
+            interleave_dir_trees(args, base, rules, sections);
+        }
+    }
+
+    match &args.mirror {
+        Some(dir) => info(
+            args,
+            format_args!(
+                "\n✅ Mirrored {}/{} files ({} -> {}) into {:?}.",
+                stats.files_processed,
+                stats.files_total,
+                format_size(stats.bytes_in),
+                format_size(stats.bytes_out),
+                dir
+            ),
+        ),
+        None => {
+            match sections {
+                SectionSink::Buffer(sections) => {
+                    write_parts(args, base, rules, &sections, &mut stats, previous)?
+                }
+                SectionSink::Stream(stream) => stream.finish(&mut stats)?,
+            }
+            info(
+                args,
+                format_args!(
+                    "\n✅ Processed {}/{} files ({} -> {}) into {} chunks.",
+                    stats.files_processed,
+                    stats.files_total,
+                    format_size(stats.bytes_in),
+                    format_size(stats.bytes_out),
+                    stats.chunks
+                ),
+            );
+            if args.limit > 0 && stats.outputs.len() > 1 {
+                print_chunk_fill(args, &stats.outputs);
+            }
+        }
+    }
+    if stats.redactions > 0 {
+        info(
+            args,
+            format_args!("🔒 Redacted {} potential secret(s).", stats.redactions),
+        );
+    }
+    if stats.minified > 0 {
+        info(
+            args,
+            format_args!(
+                "Skipped {} minified file(s) (over {} chars/line).",
+                stats.minified, args.minified_threshold
+            ),
+        );
+    }
+    if !stats.over_max_lines.is_empty() {
+        info(
+            args,
+            format_args!(
+                "Skipped {} file(s) over --max-lines {}:",
+                stats.over_max_lines.len(),
+                args.max_lines.unwrap_or_default()
+            ),
+        );
+        for (path, lines) in &stats.over_max_lines {
+            info(
+                args,
+                format_args!("  {:>8} lines  {}", lines, path.display()),
+            );
+        }
+    }
+    if !stats.cleaned.is_empty() {
+        print_comment_density(args, &mut stats.cleaned);
+    }
+    if stats.interrupted {
+        eprintln!(
+            "⚠️  Interrupted — partial dump written ({} of {} files).",
+            stats.files_processed, stats.files_total
+        );
+    }
+    Ok(stats)
+}
+
+/// `--clipboard`: puts the written chunks on the clipboard, concatenated if there are several.
+fn copy_to_clipboard(args: &Args, outputs: &[(PathBuf, u64)]) -> Result<()> {
+    let mut text = String::new();
+    for (path, _) in outputs {
+        text.push_str(
+            &fs::read_to_string(path).with_context(|| format!("Cannot read {:?}", path))?,
+        );
+    }
+    if text.len() > CLIPBOARD_WARN_SIZE {
+        eprintln!(
+            "⚠️  Copying {} to the clipboard; the paste target may truncate or reject it.",
+            format_size(text.len() as u64)
+        );
+    }
+    let len = text.len();
+    arboard::Clipboard::new()?.set_text(text)?;
+    info(
+        args,
+        format_args!(
+            "📋 Copied {}{} to the clipboard.",
+            format_size(len as u64),
+            if outputs.len() > 1 {
+                format!(" ({} chunks concatenated)", outputs.len())
+            } else {
+                String::new()
+            }
+        ),
+    );
+    Ok(())
+}
+
+/// `--comment-density`: share of each file's bytes that `--clean` removed, densest files first.
+fn print_comment_density(args: &Args, cleaned: &mut [(PathBuf, u64, u64)]) {
+    let share = |before: u64, after: u64| (before - after) as f64 * 100.0 / before.max(1) as f64;
+    let before: u64 = cleaned.iter().map(|c| c.1).sum();
+    let after: u64 = cleaned.iter().map(|c| c.2).sum();
+    info(
+        args,
+        format_args!(
+            "🧹 Removed {:.1}% as comments/whitespace ({} of {}).",
+            share(before, after),
+            format_size(before - after),
+            format_size(before)
+        ),
+    );
+    cleaned.sort_by(|a, b| {
+        share(b.1, b.2)
+            .total_cmp(&share(a.1, a.2))
+            .then(a.0.cmp(&b.0))
+    });
+    for (path, before, after) in cleaned.iter().take(10) {
+        info(
+            args,
+            format_args!(
+                "   {:>5.1}%  {} ({} removed)",
+                share(*before, *after),
+                path.display(),
+                format_size(before - after)
+            ),
+        );
+    }
+}
+
+/// How full each chunk ended up relative to `--limit`. A low fill before the last part means
+/// a large file forced an early rollover.
+fn print_chunk_fill(args: &Args, outputs: &[(PathBuf, u64)]) {
+    for (path, bytes) in outputs {
+        info(
+            args,
+            format_args!(
+                "   {}: {} / {} bytes ({:.1}%)",
+                path.file_name().unwrap_or_default().to_string_lossy(),
+                bytes,
+                args.limit,
+                *bytes as f64 * 100.0 / args.limit as f64
+            ),
+        );
+    }
+}
+
+/// Plans the parts, renders them with the preamble, and writes each chunk.
+fn write_parts(
+    args: &Args,
+    base: &Path,
+    rules: &CompiledRules,
+    sections: &[Section],
+    stats: &mut ProcessingStats,
+    previous: &Manifest,
+) -> Result<()> {
+    // Appended chunks continue the existing dump, whose first part already has the preamble.
+    let mut preamble = if args.append {
+        String::new()
+    } else {
+        build_preamble(args, base, rules)?
+    };
+    let write_started = Instant::now();
+    // render_part puts the banner at the top of the dump's first chunk.
+    let banner_len = if previous.chunks == 0 {
+        banner(args).len()
+    } else {
+        0
+    };
+    let mut parts = plan_parts(args, banner_len + preamble.len(), sections);
+    if args.summary_header && !args.append {
+        // The summary states the part count but also enlarges the first part, so replan
+        // until the count it reports is the count we write.
+        let base_preamble = preamble;
+        loop {
+            preamble = base_preamble.clone()
+                + &render_summary(args, sections, stats.bytes_in, parts.len());
+            let replanned = plan_parts(args, banner_len + preamble.len(), sections);
+            let stable = replanned.len() == parts.len();
+            parts = replanned;
+            if stable {
+                break;
+            }
+        }
+    }
+    let type_label = args.file_type.as_deref().unwrap_or("all");
+    let width = index_width(args, previous.chunks + parts.len());
+    let paths: Vec<PathBuf> = (1..=previous.chunks + parts.len())
+        .map(|i| chunk_path(&args.out, type_label, i, width))
+        .collect();
+    check_chunk_paths(&args.out, &paths)?;
+    let mut checksums = Vec::new();
+    for (i, range) in parts.iter().enumerate() {
+        let prelude = match i {
+            0 => preamble.clone(),
+            _ => {
+                let prev = &parts[i - 1];
+                let from = prev.end - args.overlap_files.min(prev.len());
+                overlap_prelude(
+                    args,
+                    sections[from..prev.end].iter().map(|s| s.text.as_str()),
+                )
+            }
+        };
+        let part = render_part(
+            args,
+            root_label(args, base),
+            previous.chunks + i + 1,
+            previous.chunks + parts.len(),
+            &prelude,
+            &sections[range.clone()],
+            previous.files.len() + range.start + 1,
+        );
+        let part = encode_output(args, part);
+        stats.bytes_out += part.len() as u64;
+        stats.chunks += 1;
+        let path = write_chunk(
+            &args.out,
+            type_label,
+            previous.chunks + stats.chunks,
+            width,
+            &part,
+        )?;
+        stats.outputs.push((path.clone(), part.len() as u64));
+        if args.checksums {
+            let digest = Sha256::digest(part.as_bytes());
+            let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+            checksums.push((hex, path.clone()));
+        }
+        if let Some(cmd) = &args.on_chunk {
+            if let Err(e) = run_chunk_hook(cmd, &path) {
+                eprintln!("⚠️  --on-chunk failed for {}: {:#}", path.display(), e);
+            }
+        }
+    }
+    let dumped = sections.iter().map(|s| &s.display_path);
+    finish_dump(args, &checksums, previous, dumped, stats)?;
+    record_time("writing", write_started);
+    Ok(())
+}
+
+/// Writes what follows the last chunk: `checksums.txt` and the `--append` manifest.
+fn finish_dump<'a>(
+    args: &Args,
+    checksums: &[(String, PathBuf)],
+    previous: &'a Manifest,
+    dumped: impl Iterator<Item = &'a PathBuf>,
+    stats: &ProcessingStats,
+) -> Result<()> {
+    if !checksums.is_empty() {
+        write_checksums(checksums, args.append)?;
+    }
+    write_manifest(
+        &manifest_path(args),
+        previous.chunks + stats.chunks,
+        previous.files.iter().chain(dumped),
+    )
+}
+
+/// Chunk layouts that need every section before the first part is written (part counts,
+/// TOCs, balancing) rule out streaming, as does `--fail-on-pattern`, which must see every
+/// file before anything is written.
+fn can_stream(args: &Args) -> bool {
+    !(args.summary_header
+        || args.part_headers
+        || args.toc
+        || args.balance_chunks
+        || args.max_parts.is_some()
+        || args.interleave_tree
+        || args.pad_index == Some(0)
+        || !args.fail_on_pattern.is_empty())
+}
+
+/// Where processed sections go: kept for `write_parts`, or streamed straight into chunks.
+enum SectionSink<'a> {
+    Buffer(Vec<Section>),
+    Stream(Box<ChunkStream<'a>>),
+}
+
+impl SectionSink<'_> {
+    fn push(&mut self, section: Section, stats: &mut ProcessingStats) -> Result<()> {
+        match self {
+            SectionSink::Buffer(sections) => {
+                sections.push(section);
+                Ok(())
+            }
+            SectionSink::Stream(stream) => stream.push(section, stats),
+        }
+    }
+}
+
+/// A chunk being written: content goes to the temp file and is renamed into place on close.
+struct OpenChunk {
+    file: BufWriter<File>,
+    tmp: PathBuf,
+    path: PathBuf,
+    hasher: Sha256,
+    bytes: u64,
+}
+
+/// Writes sections to disk as they are processed, rolling to a new chunk at `--limit`, so
+/// peak memory stays at one file rather than the whole dump. Packs exactly like `plan_parts`.
+struct ChunkStream<'a> {
+    args: &'a Args,
+    root: &'a Path,
+    previous: &'a Manifest,
+    open: Option<OpenChunk>,
+    /// Unencoded bytes in the open chunk, the measure `--limit` applies to.
+    current: usize,
+    /// Directory of the open chunk's files, for `--chunk-by-dir`.
+    dir: Option<PathBuf>,
+    checksums: Vec<(String, PathBuf)>,
+    dumped: Vec<PathBuf>,
+    /// The open chunk's last `--overlap-files` sections, repeated at the top of the next one.
+    tail: VecDeque<String>,
+}
+
+impl<'a> ChunkStream<'a> {
+    fn new(
+        args: &'a Args,
+        base: &'a Path,
+        rules: &CompiledRules,
+        previous: &'a Manifest,
+        stats: &mut ProcessingStats,
+    ) -> Result<Self> {
+        let mut stream = ChunkStream {
+            args,
+            root: root_label(args, base),
+            previous,
+            open: None,
+            current: 0,
+            dir: None,
+            checksums: Vec::new(),
+            dumped: Vec::new(),
+            tail: VecDeque::new(),
+        };
+        if previous.chunks == 0 {
+            let banner = banner(args);
+            if !banner.is_empty() {
+                stream.write(&banner, stats)?;
+            }
+        }
+        // Appended chunks continue the existing dump, whose first part already has the preamble.
+        if !args.append {
+            let preamble = build_preamble(args, base, rules)?;
+            if !preamble.is_empty() {
+                stream.write(&preamble, stats)?;
+            }
+        }
+        Ok(stream)
+    }
+
+    fn push(&mut self, section: Section, stats: &mut ProcessingStats) -> Result<()> {
+        let len = section.text.len();
+        let dir = section.display_path.parent().map(Path::to_path_buf);
+        let new_dir = self.args.chunk_by_dir && self.dir.is_some() && self.dir != dir;
+        let over_limit = self.args.limit > 0 && self.current + len > self.args.limit;
+        if self.current > 0 && (over_limit || new_dir) {
+            self.close(stats)?;
+            if !self.tail.is_empty() {
+                let prelude = overlap_prelude(self.args, self.tail.iter().map(String::as_str));
+                self.tail.clear();
+                self.write(&prelude, stats)?;
+            }
+        }
+        self.write(&section.text, stats)?;
+        self.dir = dir;
+        self.dumped.push(section.display_path);
+        if self.args.overlap_files > 0 {
+            if self.tail.len() == self.args.overlap_files {
+                self.tail.pop_front();
+            }
+            self.tail.push_back(section.text);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, text: &str, stats: &mut ProcessingStats) -> Result<()> {
+        let started = Instant::now();
+        if self.open.is_none() {
+            self.open(stats)?;
+        }
+        let out = encode_output(self.args, text.to_string());
+        self.append_raw(&out)?;
+        self.current += text.len();
+        record_time("writing", started);
+        Ok(())
+    }
+
+    fn append_raw(&mut self, out: &str) -> Result<()> {
+        let chunk = self.open.as_mut().expect("chunk is open");
+        chunk
+            .file
+            .write_all(out.as_bytes())
+            .with_context(|| format!("Cannot write {:?}", chunk.tmp))?;
+        chunk.hasher.update(out.as_bytes());
+        chunk.bytes += out.len() as u64;
+        Ok(())
+    }
+
+    fn open(&mut self, stats: &mut ProcessingStats) -> Result<()> {
+        stats.chunks += 1;
+        let type_label = self.args.file_type.as_deref().unwrap_or("all");
+        // Auto width needs the part count, so `can_stream` rules it out and this is fixed.
+        let width = self.args.pad_index.unwrap_or(0);
+        let path = chunk_path(
+            &self.args.out,
+            type_label,
+            self.previous.chunks + stats.chunks,
+            width,
+        );
+        // Chunks are only known one at a time here, so check before each new one.
+        let paths: Vec<PathBuf> = (1..=self.previous.chunks)
+            .map(|i| chunk_path(&self.args.out, type_label, i, width))
+            .chain(stats.outputs.iter().map(|(p, _)| p.clone()))
+            .chain(std::iter::once(path.clone()))
+            .collect();
+        check_chunk_paths(&self.args.out, &paths)?;
+        let tmp = temp_path_for(&path)?;
+        if let Some(p) = path.parent() {
+            fs::create_dir_all(p)?;
+        }
+        let file = File::create(&tmp).with_context(|| format!("Cannot write {:?}", tmp))?;
+        self.dir = None;
+        self.open = Some(OpenChunk {
+            file: BufWriter::new(file),
+            tmp,
+            path,
+            hasher: Sha256::new(),
+            bytes: 0,
+        });
+        self.current = 0;
+        if self.args.format == OutputFormat::Xml {
+            // Same head as `render_part` minus the part attributes, which rule out streaming.
+            let head = format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<codebase root=\"{}\">\n",
+                xml_escape(&self.root.display().to_string())
+            );
+            self.append_raw(&encode_output(self.args, head))?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, stats: &mut ProcessingStats) -> Result<()> {
+        if self.open.is_none() {
+            return Ok(());
+        }
+        let started = Instant::now();
+        if self.args.format == OutputFormat::Xml {
+            self.append_raw(&encode_output(self.args, "</codebase>\n".to_string()))?;
+        }
+        let mut chunk = self.open.take().expect("chunk is open");
+        chunk
+            .file
+            .flush()
+            .with_context(|| format!("Cannot write {:?}", chunk.tmp))?;
+        drop(chunk.file);
+        fs::rename(&chunk.tmp, &chunk.path)
+            .with_context(|| format!("Cannot move output into {:?}", chunk.path))?;
+        stats.bytes_out += chunk.bytes;
+        stats.outputs.push((chunk.path.clone(), chunk.bytes));
+        if self.args.checksums {
+            let hex: String = chunk
+                .hasher
+                .finalize()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            self.checksums.push((hex, chunk.path.clone()));
+        }
+        if let Some(cmd) = &self.args.on_chunk {
+            if let Err(e) = run_chunk_hook(cmd, &chunk.path) {
+                eprintln!(
+                    "⚠️  --on-chunk failed for {}: {:#}",
+                    chunk.path.display(),
+                    e
+                );
+            }
+        }
+        record_time("writing", started);
+        Ok(())
+    }
+
+    fn finish(mut self, stats: &mut ProcessingStats) -> Result<()> {
+        self.close(stats)?;
+        let started = Instant::now();
+        finish_dump(
+            self.args,
+            &self.checksums,
+            self.previous,
+            self.dumped.iter(),
+            stats,
+        )?;
+        record_time("writing", started);
+        Ok(())
+    }
+}
+
+/// Writes `checksums.txt` in `sha256sum` format next to the chunks, with names relative to
+/// that directory so `sha256sum -c checksums.txt` works from there.
+fn write_checksums(entries: &[(String, PathBuf)], keep_existing: bool) -> Result<()> {
+    let dir = entries[0]
+        .1
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    let path = dir.join("checksums.txt");
+    let mut out = if keep_existing {
+        fs::read_to_string(&path).unwrap_or_default()
+    } else {
+        String::new()
+    };
+    for (hex, path) in entries {
+        let name = path.strip_prefix(&dir).unwrap_or(path);
+        out.push_str(&format!("{}  {}\n", hex, name.display()));
+    }
+    write_atomic(&path, &out)
+}
+
+/// The hidden manifest lives next to the chunks so `--append` can find what's already dumped.
+fn manifest_path(args: &Args) -> PathBuf {
+    Path::new(&args.out)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .join(MANIFEST_NAME)
+}
+
+/// Format: a `chunks N` line, then one dumped display path per line.
+fn write_manifest<'a>(
+    path: &Path,
+    chunks: usize,
+    files: impl Iterator<Item = &'a PathBuf>,
+) -> Result<()> {
+    let mut out = format!("chunks {}\n", chunks);
+    for f in files {
+        out.push_str(&f.to_string_lossy());
+        out.push('\n');
+    }
+    write_atomic(path, &out)
+}
+
+fn read_manifest(path: &Path) -> Result<Manifest> {
+    let text = fs::read_to_string(path).with_context(|| {
+        format!(
+            "--append needs the manifest of a previous run, but {} is missing",
+            path.display()
+        )
+    })?;
+    let mut lines = text.lines();
+    let chunks = lines
+        .next()
+        .and_then(|l| l.strip_prefix("chunks "))
+        .and_then(|n| n.parse().ok())
+        .with_context(|| format!("{} is not a dump manifest", path.display()))?;
+    Ok(Manifest {
+        chunks,
+        files: lines.filter(|l| !l.is_empty()).map(PathBuf::from).collect(),
+    })
+}
+
+/// Applies `--encoding` and `--line-ending crlf` to text about to be written.
+fn encode_output(args: &Args, text: String) -> String {
+    let text = match args.encoding {
+        Encoding::Utf8 => text,
+        Encoding::Ascii => escape_non_ascii(&text),
+    };
+    match args.line_ending {
+        LineEnding::Crlf => text.replace('\n', "\r\n"),
+        _ => text,
+    }
+}
+
+/// Renders a binary or non-UTF8 file as base64 (76-column lines) under a `(base64)` header,
+/// if `--binary-as-base64` is set and the file is within its size cap. Mirror mode copies
+/// text only, so it never embeds.
+fn base64_section(args: &Args, cf: &CollectedFile) -> Option<Section> {
+    let cap = args.binary_as_base64?;
+    if cf.size > cap || args.mirror.is_some() {
+        return None;
+    }
+    let encoded = BASE64.encode(fs::read(&cf.path).ok()?);
+    let lines: Vec<&str> = encoded
+        .as_bytes()
+        .chunks(76)
+        .map(|c| std::str::from_utf8(c).unwrap_or_default())
+        .collect();
+    let tagged = CollectedFile {
+        tag: Some("base64"),
+        ..cf.clone()
+    };
+    Some(Section::new(
+        cf.display_path.clone(),
+        render_section(args, &tagged, &lines.join("\n")),
+    ))
+}
+
+/// `--interleave-tree`: puts a local tree of each top-level directory in front of the first
+/// file dumped from it, so structure stays close to the code it describes.
+fn interleave_dir_trees(args: &Args, base: &Path, rules: &CompiledRules, sections: &mut [Section]) {
+    let mut current = None;
+    for section in sections.iter_mut() {
+        let mut parts = section.display_path.components();
+        let top = parts.next().map(|c| c.as_os_str().to_os_string());
+        // Root-level files and external includes have no local directory to show.
+        if parts.next().is_none() || section.display_path.starts_with("[external]") {
+            current = None;
+            continue;
+        }
+        if top == current {
+            continue;
+        }
+        current = top;
+        let dir = Path::new(current.as_deref().unwrap_or_default());
+        let tree = generate_dir_tree(args, base, rules, dir);
+        section.header_line += tree.matches('\n').count();
+        section.text = tree + &section.text;
+    }
+}
+
+/// `--delimiter-style agent`: wraps a file between `<<<FILE path=.. bytes=..` and
+/// `<<<ENDFILE>>>` lines (the sentinel is `--agent-sentinel`, mirrored for the close). `bytes`
+/// counts the content as it will be written, after `--encoding` and `--line-ending`, so a
+/// reader can take exactly that many bytes after the header even if the content has
+/// lookalike lines.
+fn agent_section(args: &Args, cf: &CollectedFile, content: &str) -> String {
+    let open = &args.agent_sentinel;
+    let close: String = open
+        .chars()
+        .rev()
+        .map(|c| match c {
+            '<' => '>',
+            '[' => ']',
+            '(' => ')',
+            '{' => '}',
+            c => c,
+        })
+        .collect();
+    let end = format!("{}ENDFILE{}", open, close);
+    if content.lines().any(|l| l.trim() == end) {
+        eprintln!(
+            "⚠️  {} contains the line `{}`; split on bytes= or pick another --agent-sentinel",
+            cf.display_path.display(),
+            end
+        );
+    }
+    let path = header_path(args, cf).display().to_string();
+    let path = if path.contains(|c: char| c.is_whitespace() || c == '"' || c == '>') {
+        format!("{:?}", path)
+    } else {
+        path
+    };
+    let status = cf.tag.map(|t| format!(" status={}", t)).unwrap_or_default();
+    format!(
+        "\n{}FILE path={} bytes={}{}{}\n{}\n{}\n",
+        open,
+        path,
+        encode_output(args, content.to_string()).len(),
+        status,
+        close,
+        content,
+        end
+    )
+}
+
+/// The path shown in a file's header: relative to `--relativize-to` when given (absolute for
+/// files outside it), otherwise the display path.
+fn header_path(args: &Args, cf: &CollectedFile) -> PathBuf {
+    let Some(dir) = &args.relativize_to else {
+        return cf.display_path.clone();
+    };
+    // Canonicalize the parent only, so a symlinked file keeps its own name.
+    let path = match (cf.path.parent(), cf.path.file_name()) {
+        (Some(parent), Some(name)) => fs::canonicalize(parent)
+            .map(|p| p.join(name))
+            .unwrap_or_else(|_| cf.path.clone()),
+        _ => cf.path.clone(),
+    };
+    match path.strip_prefix(dir) {
+        Ok(rel) => rel.to_path_buf(),
+        Err(_) => path,
+    }
+}
+
+/// Wraps processed content in the file delimiters from `--file-header-template` and
+/// `--file-footer-template` (default: `--- FILE: path ---` and no footer).
+fn render_section(args: &Args, cf: &CollectedFile, content: &str) -> String {
+    let mut notes = String::new();
+    match cf.tag {
+        Some("base64") => notes.push_str(" (base64)"),
+        Some(tag) => notes.push_str(&format!(" [{}]", tag)),
+        None => {}
+    }
+    // Timestamps would break byte-identical output, so --reproducible drops them.
+    if args.show_mtime && !args.reproducible {
+        if let Ok(modified) = fs::metadata(&cf.path).and_then(|m| m.modified()) {
+            notes.push_str(&format!(" (modified {})", format_timestamp(modified)));
+        }
+    }
+    if args.format == OutputFormat::Xml {
+        let mut attrs = format!(
+            "path=\"{}\" bytes=\"{}\"",
+            xml_escape(&header_path(args, cf).display().to_string()),
+            content.len()
+        );
+        if let Some(tag) = cf.tag {
+            attrs.push_str(&format!(" status=\"{}\"", tag));
+        }
+        return format!("<file {}>{}</file>\n", attrs, cdata(content));
+    }
+    if args.delimiter_style == DelimiterStyle::Agent {
+        return agent_section(args, cf, content);
+    }
+    let relpath = header_path(args, cf).display().to_string();
+    let fill = |template: &str| {
+        unescape(template)
+            .replace("{relpath}", &relpath)
+            .replace("{path}", &cf.path.display().to_string())
+            .replace(
+                "{ext}",
+                &cf.path
+                    .extension()
+                    .map(|e| e.to_string_lossy())
+                    .unwrap_or_default(),
+            )
+            .replace("{lines}", &content.lines().count().to_string())
+            .replace("{bytes}", &content.len().to_string())
+            .replace("{notes}", &notes)
+    };
+    let mut out = format!("\n{}\n{}\n", fill(&args.file_header_template), content);
+    if !args.file_footer_template.is_empty() {
+        out.push_str(&fill(&args.file_footer_template));
+        out.push('\n');
+    }
+    out
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Wraps text in CDATA, splitting any `]]>` in the content so the section stays well-formed.
+fn cdata(s: &str) -> String {
+    format!("<![CDATA[{}]]>", s.replace("]]>", "]]]]><![CDATA[>"))
+}
+
+/// Replaces every non-ASCII character with `\uXXXX` (UTF-16 surrogate pairs beyond the BMP),
+/// so identifiers survive a round trip through ASCII-only tooling.
+fn escape_non_ascii(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c.is_ascii() {
+            out.push(c);
+        } else {
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                out.push_str(&format!("\\u{:04X}", unit));
+            }
+        }
+    }
+    out
+}
+
+/// Expands `\n` and `\t` escapes so templates can span lines from the command line.
+fn unescape(s: &str) -> String {
+    s.replace("\\n", "\n").replace("\\t", "\t")
+}
+
+/// Everything that precedes the first file section: git metadata and the project tree.
+fn build_preamble(args: &Args, base: &Path, rules: &CompiledRules) -> Result<String> {
+    let started = Instant::now();
+    let xml = args.format == OutputFormat::Xml;
+    let mut out = String::new();
+    // Context documents lead the dump verbatim: no type filter, cleaning, or redaction.
+    for path in &args.context_file {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read --context-file {:?}", path))?;
+        if xml {
+            out.push_str(&format!(
+                "<context path=\"{}\">{}</context>\n",
+                xml_escape(&path.display().to_string()),
+                cdata(&text)
+            ));
+        } else {
+            out.push_str(&format!(
+                "--- CONTEXT: {} ---\n{}\n\n",
+                path.display(),
+                text.trim_end()
+            ));
+        }
+    }
+    if args.git_info {
+        if let Some(info) = git_info(base) {
+            if xml {
+                out.push_str(&format!("<git>{}</git>\n", xml_escape(info.trim())));
+            } else {
+                out.push_str(&info);
+            }
+        }
+    }
+    if !args.no_tree {
+        let tree = generate_tree(args, base, rules);
+        // A sidecar tree keeps the structure out of the first chunk's budget.
+        if let Some(path) = &args.tree_file {
+            write_atomic(path, &encode_output(args, tree))?;
+        } else if xml {
+            out.push_str(&format!("<structure>{}</structure>\n", cdata(&tree)));
+        } else {
+            out.push_str(&tree);
+        }
+    }
+    record_time("tree", started);
+    Ok(out)
+}
+
+/// Scope summary for `--summary-header`: file count, input bytes, languages, and part count.
+fn render_summary(args: &Args, sections: &[Section], bytes: u64, parts: usize) -> String {
+    let langs: BTreeSet<&str> = sections
+        .iter()
+        .map(|s| language_for(&s.display_path))
+        .collect();
+    let langs = langs.into_iter().collect::<Vec<_>>().join(", ");
+    if args.format == OutputFormat::Xml {
+        return format!(
+            "<summary files=\"{}\" bytes=\"{}\" parts=\"{}\" languages=\"{}\"/>\n",
+            sections.len(),
+            bytes,
+            parts,
+            xml_escape(&langs)
+        );
+    }
+    format!(
+        "SUMMARY\n{}\nFiles: {}\nSize: {}\nLanguages: {}\nParts: {}\n{}\n",
+        "=".repeat(40),
+        sections.len(),
+        format_size(bytes),
+        langs,
+        parts,
+        "=".repeat(40)
+    )
+}
+
+/// How the source root is labelled in output: relative to `--relativize-to` when it lies
+/// inside it. The absolute root is machine-specific, so reproducible dumps label it
+/// relatively.
+fn root_label<'a>(args: &Args, base: &'a Path) -> &'a Path {
+    let relative = args
+        .relativize_to
+        .as_deref()
+        .and_then(|dir| base.strip_prefix(dir).ok());
+    if let Some(rel) = relative {
+        if rel.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            rel
+        }
+    } else if args.reproducible {
+        Path::new(".")
+    } else {
+        base
+    }
+}
+
+/// Commit, branch, and dirty state of the repository containing `base`, or `None` when it
+/// is not a git checkout (or git is unavailable).
+fn git_info(base: &Path) -> Option<String> {
+    let git = |args: &[&str]| -> Option<String> {
+        let out = Command::new("git")
+            .arg("-C")
+            .arg(base)
+            .args(args)
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        out.status
+            .success()
+            .then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+    };
+    let commit = git(&["rev-parse", "HEAD"])?;
+    let branch = git(&["rev-parse", "--abbrev-ref", "HEAD"]).unwrap_or_default();
+    let dirty = git(&["status", "--porcelain"]).is_some_and(|s| !s.is_empty());
+    Some(format!(
+        "GIT: commit {} on branch {} ({})\n",
+        commit,
+        branch,
+        if dirty { "dirty" } else { "clean" }
+    ))
+}
+
+/// Splits sections into output parts, returned as index ranges. Files are never split: a
+/// part rolls over only between sections, once adding the next one would exceed `--limit`
+/// (`0` = no limit). The preamble (tree) counts toward the first part.
+///
+/// Invariant: every `--- FILE` section lands whole in exactly one part. Parts are ranges over
+/// whole sections, so a file larger than `--limit` gets an oversized part of its own rather
+/// than being cut. Any future opt-in splitting of large files must keep this the default.
+///
+/// With `--balance-chunks` the part count stays `ceil(total / limit)` but each part aims for
+/// `total / parts` bytes, avoiding a tiny trailing part. File order is preserved.
+///
+/// `--max-parts` raises the effective limit to `ceil(total / max_parts)` and, should packing
+/// still need more parts, merges the overflow into the last one.
+fn plan_parts(args: &Args, preamble_len: usize, sections: &[Section]) -> Vec<Range<usize>> {
+    let mut remaining = preamble_len + sections.iter().map(|s| s.text.len()).sum::<usize>();
+    let limit = match args.max_parts {
+        Some(max) if args.limit > 0 => args.limit.max(remaining.div_ceil(max.max(1))),
+        _ => args.limit,
+    };
+    let balance = args.balance_chunks && limit > 0;
+    let planned = remaining.div_ceil(limit.max(1)).max(1);
+    let mut target = remaining.div_ceil(planned);
+
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut current = preamble_len;
+    // Bytes of the open part repeated from the previous one (`--overlap-files`).
+    let mut carried = 0;
+    for (i, section) in sections.iter().enumerate() {
+        let len = section.text.len();
+        let over_limit = limit > 0 && current + len > limit;
+        let capped = args.max_parts.is_some_and(|max| parts.len() + 1 >= max);
+        // Close a balanced part when stopping short of the target is closer than overshooting.
+        let over_target = balance
+            && parts.len() + 1 < planned
+            && current + len > target
+            && current + len - target > target.saturating_sub(current);
+        // --chunk-by-dir: a part never mixes files from different directories.
+        let new_dir = args.chunk_by_dir
+            && i > start
+            && sections[i - 1].display_path.parent() != section.display_path.parent();
+        if current > 0 && !capped && (over_limit || over_target || new_dir) {
+            parts.push(start..i);
+            let from = i - args.overlap_files.min(i - start);
+            start = i;
+            remaining -= current - carried;
+            carried =
+                overlap_prelude(args, sections[from..i].iter().map(|s| s.text.as_str())).len();
+            current = carried;
+            target = remaining.div_ceil(planned.saturating_sub(parts.len()).max(1));
+        }
+        current += len;
+    }
+    if current > 0 {
+        parts.push(start..sections.len());
+    }
+    parts
+}
+
+/// `--overlap-files`: the previous part's last sections, marked as repeated context.
+fn overlap_prelude<'a>(args: &Args, texts: impl Iterator<Item = &'a str>) -> String {
+    let mut texts = texts.peekable();
+    if texts.peek().is_none() {
+        return String::new();
+    }
+    let (open, close) = match args.format {
+        OutputFormat::Xml => (
+            "<!-- (repeated for context) -->\n",
+            "<!-- (end of repeated context) -->\n",
+        ),
+        OutputFormat::Text => (
+            "--- (repeated for context) ---\n",
+            "--- (end of repeated context) ---\n",
+        ),
+    };
+    let mut prelude = String::from(open);
+    texts.for_each(|t| prelude.push_str(t));
+    prelude.push_str(close);
+    prelude
+}
+
+/// `--banner`: the text exactly as given, heading the dump's first chunk.
+fn banner(args: &Args) -> String {
+    match &args.banner {
+        None => String::new(),
+        Some(text) if args.format == OutputFormat::Xml => {
+            format!("<banner>{}</banner>\n", cdata(text))
+        }
+        Some(text) => format!("{}\n\n", text.trim_end_matches('\n')),
+    }
+}
+
+/// Assembles one output part: optional `--part-headers` line, optional `--toc`, the
+/// preamble (first part) or `--overlap-files` prelude (later parts), then the file
+/// sections. `first_file` is the 1-based global number of the part's first section.
+fn render_part(
+    args: &Args,
+    root: &Path,
+    index: usize,
+    total: usize,
+    preamble: &str,
+    sections: &[Section],
+    first_file: usize,
+) -> String {
+    let xml = args.format == OutputFormat::Xml;
+    let mut head = String::new();
+    if xml {
+        // Each part is its own well-formed document.
+        head.push_str(&format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<codebase root=\"{}\"",
+            xml_escape(&root.display().to_string())
+        ));
+        if args.part_headers {
+            head.push_str(&format!(" part=\"{}\" parts=\"{}\"", index, total));
+        }
+        head.push_str(">\n");
+    }
+    if index == 1 {
+        head.push_str(&banner(args));
+    }
+    if !xml && args.part_headers {
+        let files = if sections.is_empty() {
+            "project structure".to_string()
+        } else {
+            format!("files {}–{}", first_file, first_file + sections.len() - 1)
+        };
+        head.push_str(&format!(
+            "=== PART {} of {} — {} ===\n",
+            index, total, files
+        ));
+    }
+
+    let mut body = String::from(preamble);
+    let mut toc = Vec::new();
+    for section in sections {
+        toc.push((
+            &section.display_path,
+            body.matches('\n').count() + section.header_line + 1,
+        ));
+        body.push_str(&section.text);
+    }
+    if xml {
+        body.push_str("</codebase>\n");
+    }
+
+    if args.toc && !toc.is_empty() {
+        let offset = head.lines().count() + toc.len() + 3 + 2 * usize::from(xml);
+        head.push_str(&format!(
+            "{}TABLE OF CONTENTS\n{}\n",
+            if xml { "<!--\n" } else { "" },
+            "=".repeat(40)
+        ));
+        for (i, (path, line)) in toc.iter().enumerate() {
+            head.push_str(&format!(
+                "{:>4}. {} (line {})\n",
+                i + 1,
+                path.display(),
+                line + offset
+            ));
+        }
+        head.push_str(&"=".repeat(40));
+        head.push_str(if xml { "\n-->\n" } else { "\n" });
+    }
+    head + &body
+}
+
+// ============================================================================
+// HELPERS
+// ============================================================================
 
-        let header = format!("\n--- FILE: {} ---\n", cf.display_path.display());
-        if !buffer.is_empty() && (buffer.len() + header.len() + processed.len() > args.limit) {
-            stats.bytes_out += buffer.len() as u64;
-            stats.chunks += 1;
-            write_chunk(&args.out, type_label, stats.chunks, &buffer)?;
-            buffer.clear();
-        }
-        buffer.push_str(&header);
-        buffer.push_str(&processed);
-        buffer.push('\n');
-        stats.files_processed += 1;
+/// Prints a status message to stdout unless `--quiet` is set.
+fn info(args: &Args, msg: std::fmt::Arguments) {
+    if !args.quiet {
+        println!("{}", msg);
     }
+}
 
-    if !buffer.is_empty() {
-        stats.bytes_out += buffer.len() as u64;
-        stats.chunks += 1;
-        write_chunk(&args.out, type_label, stats.chunks, &buffer)?;
+/// Logs a collection/processing decision to stderr when `-v` is given at least `level` times.
+fn trace(args: &Args, level: u8, msg: std::fmt::Arguments) {
+    if args.verbose >= level {
+        eprintln!("{}", msg);
     }
+}
 
-    if let Some(ref p) = pb {
-        p.finish_and_clear();
+fn record_time(phase: &'static str, since: Instant) {
+    add_time(phase, since.elapsed());
+}
+
+fn add_time(phase: &'static str, elapsed: Duration) {
+    let mut timings = TIMINGS.lock().unwrap_or_else(|e| e.into_inner());
+    match timings.iter_mut().find(|(p, _)| *p == phase) {
+        Some((_, total)) => *total += elapsed,
+        None => timings.push((phase, elapsed)),
     }
-    println!(
-        "\n✅ Processed {}/{} files ({} -> {}) into {} chunks.",
-        stats.files_processed,
-        stats.files_total,
-        format_size(stats.bytes_in),
-        format_size(stats.bytes_out),
-        stats.chunks
+}
+
+fn phase_time(phase: &str) -> Duration {
+    TIMINGS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .find(|(p, _)| *p == phase)
+        .map(|(_, d)| *d)
+        .unwrap_or_default()
+}
+
+/// `--timing`: the phase table. Phases that didn't run (e.g. `tree` under `--no-tree`) still
+/// show, at zero.
+fn print_timings(started: Instant) {
+    eprintln!("⏱  Timing:");
+    for phase in ["setup", "collection", "tree", "processing", "writing"] {
+        let ms = phase_time(phase).as_secs_f64() * 1000.0;
+        eprintln!("   {:12} {:>9.1} ms", phase, ms);
+    }
+    eprintln!(
+        "   {:12} {:>9.1} ms",
+        "total",
+        started.elapsed().as_secs_f64() * 1000.0
     );
-    Ok(())
 }
 
-// ============================================================================
-// HELPERS
-// ============================================================================
+/// Reports a dropped path: always under `-v`, and under `--show-excluded` when the path was
+/// a real candidate (it matched `--type` or an include before being filtered out).
+fn log_skip(args: &Args, candidate: bool, path: &Path, reason: std::fmt::Arguments) {
+    if args.verbose >= 1 || (args.show_excluded && candidate) {
+        eprintln!("skip    {} ({})", path.display(), reason);
+    }
+    if args.report.is_some() {
+        SKIPPED
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((path.display().to_string(), reason.to_string()));
+    }
+}
+
+/// Whether `path` passes the `--type` extension filter (`None` accepts everything).
+fn matches_type(path: &Path, target_ext: Option<&str>, rules: &CompiledRules) -> bool {
+    match target_ext {
+        Some(target) => file_ext(path, rules) == target,
+        None => true,
+    }
+}
+
+fn compile_globs(patterns: &[String]) -> Vec<(String, Regex)> {
+    expand_braces(patterns)
+        .into_iter()
+        .filter_map(|p| glob_to_regex(&p).map(|re| (p, re)))
+        .collect()
+}
 
 fn glob_to_regex(pattern: &str) -> Option<Regex> {
     let mut re = String::from("(?i)");
@@ -483,76 +3442,1027 @@ fn is_likely_text(path: &Path) -> bool {
     !buf[..n].contains(&0)
 }
 
-fn clean_content(path: &Path, content: &str) -> String {
-    let ext = path
-        .extension()
-        .map(|e| e.to_string_lossy().to_lowercase())
-        .unwrap_or_default();
+/// Scans the head of the file for a built-in or user-supplied generated-code marker.
+fn find_generated_marker(path: &Path, extra: &[String]) -> Option<String> {
+    let mut buf = Vec::with_capacity(GENERATED_SCAN_BYTES);
+    File::open(path)
+        .ok()?
+        .take(GENERATED_SCAN_BYTES as u64)
+        .read_to_end(&mut buf)
+        .ok()?;
+    let head = String::from_utf8_lossy(&buf);
+    GENERATED_MARKERS
+        .iter()
+        .copied()
+        .chain(extra.iter().map(String::as_str))
+        .find(|m| !m.is_empty() && head.contains(m))
+        .map(str::to_string)
+}
+
+/// Compacts data files for `--minify-data`: JSON is re-serialized, YAML loses blank lines and
+/// trailing spaces outside block scalars (indentation is significant), XML loses whitespace
+/// between tags.
+/// Returns `None` for other extensions and `Some(Err)` for malformed JSON.
+fn minify_data(ext: &str, content: &str) -> Option<Result<String>> {
+    match ext {
+        "json" => Some(
+            serde_json::from_str::<serde_json::Value>(content)
+                .map(|v| v.to_string())
+                .map_err(Into::into),
+        ),
+        "yaml" | "yml" => Some(Ok(minify_yaml(content))),
+        "xml" => Some(Ok(XML_GAP_REGEX
+            .replace_all(content.trim(), "><")
+            .into_owned())),
+        _ => None,
+    }
+}
+
+/// Drops blank lines and trailing spaces, except inside `|`/`>` block scalars, where both
+/// are part of the value and are kept verbatim.
+fn minify_yaml(content: &str) -> String {
+    let indent = |l: &str| l.len() - l.trim_start().len();
+    let mut out = Vec::new();
+    // Indentation of the line that opened the current block scalar.
+    let mut block: Option<usize> = None;
+    for line in content.lines() {
+        if let Some(parent) = block {
+            if line.trim().is_empty() || indent(line) > parent {
+                out.push(line);
+                continue;
+            }
+            block = None;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        if YAML_BLOCK_REGEX.is_match(line) {
+            block = Some(indent(line));
+        }
+        out.push(line);
+    }
+    // Blank lines closing the file were only kept in case the block went on.
+    while out.last().is_some_and(|l| l.trim().is_empty()) {
+        out.pop();
+    }
+    out.join("\n")
+}
+
+/// `--path-as-comment`: the file's path as a comment in its own language. Formats without
+/// comment syntax (JSON, Markdown, plain text, ...) get none.
+fn path_comment(args: &Args, path: &Path, rules: &CompiledRules) -> Option<String> {
+    if !args.path_as_comment {
+        return None;
+    }
+    let ext = file_ext(path, rules);
+    if NO_COMMENT_EXTS.contains(&ext.as_str()) {
+        return None;
+    }
     let name = path
         .file_name()
         .map(|n| n.to_string_lossy().to_lowercase())
         .unwrap_or_default();
-    let regex = match ext.as_str() {
-        "py" | "rb" | "sh" | "yml" | "yaml" | "toml" | "env" => &*SCRIPT_STYLE_REGEX,
-        "php" => &*PHP_STYLE_REGEX,
-        "sql" => &*SQL_STYLE_REGEX,
-        "html" | "xml" | "svg" => &*HTML_STYLE_REGEX,
-        _ if name == "dockerfile" || name == "makefile" => &*SCRIPT_STYLE_REGEX,
-        _ => &*C_STYLE_REGEX,
+    let style = CommentStyle::for_file(&ext, &name, rules.comment_fallback);
+    Some(style.line_comment(&path.display().to_string()))
+}
+
+/// `--outline`: only the declaration lines of `content`, bodies replaced by `{ ... }` (or
+/// `...` after a Python `:`). A signature spanning several lines is kept up to where its
+/// body opens. `None` when there are no outline patterns for `ext`.
+fn outline(ext: &str, content: &str) -> Option<String> {
+    let (_, re) = OUTLINE_PATTERNS
+        .iter()
+        .find(|(exts, _)| exts.contains(&ext))?;
+    let colon = matches!(ext, "py" | "pyi" | "pyw");
+    // Ruby and shell signatures have no terminator, so they are always one line.
+    let single = matches!(ext, "rb" | "sh" | "bash" | "zsh");
+    let opens = |l: &str| {
+        let l = l.trim_end();
+        if colon {
+            l.ends_with(':')
+        } else {
+            l.contains('{') || l.ends_with(';')
+        }
     };
+    let lines: Vec<&str> = content.lines().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < lines.len() {
+        if !re.is_match(lines[i]) {
+            i += 1;
+            continue;
+        }
+        let mut end = i;
+        while !single && !opens(lines[end]) && end + 1 < lines.len() && end - i < 8 {
+            end += 1;
+        }
+        for line in &lines[i..end] {
+            out.push_str(line.trim_end());
+            out.push('\n');
+        }
+        let last = lines[end].trim_end();
+        let last = match last.find('{') {
+            Some(brace) if !colon => format!("{}{{ ... }}", &last[..brace]),
+            _ if colon && last.ends_with(':') => format!("{} ...", last),
+            _ => last.to_string(),
+        };
+        out.push_str(&last);
+        out.push('\n');
+        i = end + 1;
+    }
+    if out.is_empty() {
+        out.push_str("(no declarations)\n");
+    }
+    Some(out)
+}
+
+/// Removes import statements (see `IMPORT_PATTERNS`), plus the blank lines they leave at the
+/// top of the file. Unknown extensions are returned unchanged.
+fn strip_imports(path: &Path, content: String, rules: &CompiledRules) -> String {
+    let ext = file_ext(path, rules);
+    match IMPORT_PATTERNS
+        .iter()
+        .find(|(exts, _)| exts.contains(&ext.as_str()))
+    {
+        Some((_, re)) => re
+            .replace_all(&content, "")
+            .trim_start_matches(['\n', '\r'])
+            .to_string(),
+        None => content,
+    }
+}
+
+/// `--clean-diff`: a unified diff from the original to the cleaned content, so what the
+/// cleaner removes can be reviewed before trusting it.
+fn clean_diff(path: &Path, original: &str, cleaned: &str) -> String {
+    // The cleaner drops the final newline; that alone is not worth a hunk.
+    let original = format!("{}\n", original.trim_end_matches('\n'));
+    let cleaned = format!("{}\n", cleaned.trim_end_matches('\n'));
+    let diff = similar::TextDiff::from_lines(&original, &cleaned)
+        .unified_diff()
+        .header(
+            &format!("a/{}", path.display()),
+            &format!("b/{} (cleaned)", path.display()),
+        )
+        .to_string();
+    if diff.is_empty() {
+        "(cleaning removes nothing)\n".to_string()
+    } else {
+        diff
+    }
+}
+
+fn clean_content(args: &Args, path: &Path, content: &str, rules: &CompiledRules) -> String {
+    let ext = file_ext(path, rules);
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let regex = CommentStyle::for_file(&ext, &name, rules.comment_fallback).regex();
     let cleaned = regex.replace_all(content, |caps: &Captures| {
-        caps.name("keep")
-            .map(|m| m.as_str())
-            .unwrap_or("")
-            .to_string()
+        if let Some(m) = caps.name("keep") {
+            return m.as_str().to_string();
+        }
+        // Comments carrying an actionable marker (`--keep-comment-marker`) survive cleaning.
+        let comment = caps.name("drop").map(|m| m.as_str()).unwrap_or("");
+        if args
+            .keep_comment_marker
+            .iter()
+            .any(|m| !m.is_empty() && comment.contains(m.as_str()))
+        {
+            comment.to_string()
+        } else if args.keep_empty_lines {
+            REMOVED_MARK.to_string()
+        } else {
+            String::new()
+        }
     });
+    if args.keep_empty_lines {
+        // Blank lines from the source stay; lines that only held a removed comment go.
+        return cleaned
+            .split_inclusive('\n')
+            .filter(|line| {
+                !(line.contains(REMOVED_MARK)
+                    && line.chars().all(|c| c == REMOVED_MARK || c.is_whitespace()))
+            })
+            .collect::<String>()
+            .replace(REMOVED_MARK, "")
+            .trim()
+            .to_string();
+    }
     EMPTY_LINES_REGEX
         .replace_all(&cleaned, "\n")
         .trim()
         .to_string()
 }
 
-fn write_chunk(pattern: &str, file_type: &str, index: usize, content: &str) -> Result<()> {
-    let path_str = pattern
-        .replace("{index}", &index.to_string())
-        .replace("{type}", file_type)
-        .replace('*', &index.to_string());
-    let path = PathBuf::from(path_str);
+/// Lines of `content` matching any `--fail-on-pattern`, as 1-based numbers with the line
+/// trimmed (and shortened) for the report.
+fn forbidden_lines(content: &str, patterns: &[Regex]) -> Vec<(usize, String)> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| patterns.iter().any(|re| re.is_match(line)))
+        .map(|(i, line)| {
+            let line = line.trim();
+            let shown = match line.char_indices().nth(120) {
+                Some((end, _)) => format!("{}…", &line[..end]),
+                None => line.to_string(),
+            };
+            (i + 1, shown)
+        })
+        .collect()
+}
+
+/// Masks secrets with `[REDACTED]`: the built-in patterns and high-entropy tokens when
+/// `builtin` is set, plus any `--redact-pattern`s. Returns the text and the number of masks.
+fn redact_secrets(content: &str, builtin: bool, extra: &[Regex]) -> (String, usize) {
+    let mut count = 0;
+    let mut text = content.to_string();
+    let patterns = SECRET_PATTERNS
+        .iter()
+        .filter(|_| builtin)
+        .chain(extra.iter());
+    for re in patterns {
+        text = re
+            .replace_all(&text, |caps: &Captures| {
+                count += 1;
+                let whole = caps.get(0).expect("match");
+                match caps.name("secret") {
+                    Some(s) => {
+                        let (start, end) = (s.start() - whole.start(), s.end() - whole.start());
+                        let m = whole.as_str();
+                        format!("{}[REDACTED]{}", &m[..start], &m[end..])
+                    }
+                    None => "[REDACTED]".to_string(),
+                }
+            })
+            .into_owned();
+    }
+    if builtin {
+        text = TOKEN_REGEX
+            .replace_all(&text, |caps: &Captures| {
+                let token = &caps[0];
+                if token.bytes().any(|b| b.is_ascii_digit()) && shannon_entropy(token) > 4.5 {
+                    count += 1;
+                    "[REDACTED]".to_string()
+                } else {
+                    token.to_string()
+                }
+            })
+            .into_owned();
+    }
+    (text, count)
+}
+
+/// Bits of entropy per character; random keys score well above English-like identifiers.
+fn shannon_entropy(s: &str) -> f64 {
+    let mut counts = [0usize; 256];
+    for b in s.bytes() {
+        counts[b as usize] += 1;
+    }
+    let len = s.len() as f64;
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Parses repeatable `--replace pattern=replacement` specs, split at the first `=`.
+fn parse_replacements(specs: &[String]) -> Result<Vec<(Regex, String)>> {
+    specs
+        .iter()
+        .map(|spec| {
+            let (pat, with) = spec.split_once('=').with_context(|| {
+                format!("Invalid --replace {:?}: expected pattern=replacement", spec)
+            })?;
+            let re =
+                Regex::new(pat).with_context(|| format!("Invalid --replace pattern {:?}", pat))?;
+            Ok((re, with.to_string()))
+        })
+        .collect()
+}
+
+/// Parses `--treat-as NAME=EXT` specs into lowercased (file name, extension) pairs; a
+/// leading dot on the extension is dropped.
+fn parse_treat_as(specs: &[String]) -> Result<Vec<(String, String)>> {
+    specs
+        .iter()
+        .map(|spec| {
+            let (name, ext) = spec
+                .split_once('=')
+                .filter(|(n, e)| !n.trim().is_empty() && !e.trim().is_empty())
+                .with_context(|| format!("Invalid --treat-as {:?}: expected NAME=EXT", spec))?;
+            Ok((
+                name.trim().to_lowercase(),
+                ext.trim().trim_start_matches('.').to_lowercase(),
+            ))
+        })
+        .collect()
+}
+
+/// The lowercased extension a file is handled as: its `--treat-as` mapping if the file name
+/// has one, otherwise its real extension (empty if none).
+fn file_ext(path: &Path, rules: &CompiledRules) -> String {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if let Some((_, ext)) = rules.treat_as.iter().find(|(n, _)| *n == name) {
+        return ext.clone();
+    }
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+/// Parses repeatable `--clean-cmd ext=command args...` specs into extension -> argv.
+fn parse_clean_cmds(specs: &[String]) -> Result<HashMap<String, Vec<String>>> {
+    let mut cmds = HashMap::new();
+    for spec in specs {
+        let (ext, cmd) = spec
+            .split_once('=')
+            .with_context(|| format!("Invalid --clean-cmd {:?}: expected ext=command", spec))?;
+        let argv: Vec<String> = cmd.split_whitespace().map(str::to_string).collect();
+        if argv.is_empty() {
+            anyhow::bail!("Invalid --clean-cmd {:?}: empty command", spec);
+        }
+        cmds.insert(ext.trim().trim_start_matches('.').to_lowercase(), argv);
+    }
+    Ok(cmds)
+}
+
+/// Pipes `content` through an external cleaner and returns its stdout.
+fn run_clean_cmd(argv: &[String], content: &str) -> Result<String> {
+    let mut child = Command::new(&argv[0])
+        .args(&argv[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("cannot run `{}`", argv[0]))?;
+    // Feed stdin from a separate thread so a chatty child can't deadlock on a full pipe.
+    let mut stdin = child.stdin.take().context("no stdin")?;
+    let input = content.to_string();
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output()?;
+    let _ = writer.join();
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!(
+            "`{}` exited with {} {}",
+            argv.join(" "),
+            output.status,
+            stderr.trim()
+        );
+    }
+    String::from_utf8(output.stdout).context("cleaner produced non-UTF8 output")
+}
+
+/// Applies `--tabs-to-spaces` and `--trim-trailing`. These are naive line transforms:
+/// whitespace inside string literals is rewritten too.
+fn normalize_whitespace(args: &Args, content: String) -> String {
+    if !args.trim_trailing && args.tabs_to_spaces.is_none() {
+        return content;
+    }
+    let tab = args.tabs_to_spaces.map(|n| " ".repeat(n));
+    let mut out = String::with_capacity(content.len());
+    for line in content.split_inclusive('\n') {
+        let (text, eol) = match line.strip_suffix('\n') {
+            Some(t) => (t, "\n"),
+            None => (line, ""),
+        };
+        let text = match &tab {
+            Some(spaces) => text.replace('\t', spaces),
+            None => text.to_string(),
+        };
+        out.push_str(if args.trim_trailing {
+            text.trim_end()
+        } else {
+            &text
+        });
+        out.push_str(eol);
+    }
+    out
+}
+
+/// Hard-wraps lines longer than `width` characters, preferring the last space before the
+/// limit. Continuation lines start with `WRAP_MARKER`. This is a naive column wrap: it does
+/// not know about strings or syntax.
+fn wrap_lines(content: &str, width: usize) -> String {
+    let cont_width = width.saturating_sub(WRAP_MARKER.chars().count()).max(1);
+    let mut out = String::with_capacity(content.len());
+    for (n, line) in content.split('\n').enumerate() {
+        if n > 0 {
+            out.push('\n');
+        }
+        let mut rest: Vec<char> = line.chars().collect();
+        let mut budget = width;
+        while rest.len() > budget {
+            let cut = rest[..budget]
+                .iter()
+                .rposition(|c| c.is_whitespace())
+                .filter(|&i| i > 0)
+                .unwrap_or(budget);
+            out.extend(&rest[..cut]);
+            out.push('\n');
+            out.push_str(WRAP_MARKER);
+            let skip = if cut < rest.len() && rest[cut].is_whitespace() {
+                1
+            } else {
+                0
+            };
+            rest.drain(..cut + skip);
+            budget = cont_width;
+        }
+        out.extend(rest);
+    }
+    out
+}
+
+/// Keeps only lines matching `re` plus `context` lines around them, grep-style, with `--`
+/// between non-adjacent hunks. Returns `None` when nothing matches.
+fn grep_lines(content: &str, re: &Regex, context: usize) -> Option<String> {
+    let lines: Vec<&str> = content.lines().collect();
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        if !re.is_match(line) {
+            continue;
+        }
+        let (start, end) = (
+            i.saturating_sub(context),
+            (i + context).min(lines.len() - 1),
+        );
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 + 1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    if hunks.is_empty() {
+        return None;
+    }
+    Some(
+        hunks
+            .iter()
+            .map(|&(start, end)| lines[start..=end].join("\n"))
+            .collect::<Vec<_>>()
+            .join("\n--\n"),
+    )
+}
+
+/// `width` zero-pads the index (`dump_01.txt`) so chunks sort correctly as names.
+fn chunk_path(pattern: &str, file_type: &str, index: usize, width: usize) -> PathBuf {
+    let index = format!("{:0width$}", index, width = width);
+    PathBuf::from(
+        pattern
+            .replace("{index}", &index)
+            .replace("{type}", file_type)
+            .replace('*', &index),
+    )
+}
+
+/// Fails, listing the clashes, when several chunk numbers resolve to the same output file
+/// (e.g. an `--out` without `{index}` or `*`), instead of letting later chunks overwrite
+/// earlier ones. `paths[i]` is the path of chunk `i + 1`.
+fn check_chunk_paths(pattern: &str, paths: &[PathBuf]) -> Result<()> {
+    let mut chunks: HashMap<&PathBuf, Vec<usize>> = HashMap::new();
+    for (i, path) in paths.iter().enumerate() {
+        chunks.entry(path).or_default().push(i + 1);
+    }
+    let mut clashes: Vec<_> = chunks.into_iter().filter(|(_, c)| c.len() > 1).collect();
+    if clashes.is_empty() {
+        return Ok(());
+    }
+    clashes.sort_by_key(|(_, c)| c[0]);
+    let list: Vec<String> = clashes
+        .iter()
+        .map(|(path, c)| {
+            let nums: Vec<String> = c.iter().map(usize::to_string).collect();
+            format!("  {} <- chunks {}", path.display(), nums.join(", "))
+        })
+        .collect();
+    anyhow::bail!(
+        "--out {:?} maps several chunks to the same file; add {{index}} or *:\n{}",
+        pattern,
+        list.join("\n")
+    )
+}
+
+/// Index width for `--pad-index`: as given, or the digits of `total` parts when bare.
+fn index_width(args: &Args, total: usize) -> usize {
+    match args.pad_index {
+        Some(0) => total.to_string().len(),
+        Some(width) => width,
+        None => 0,
+    }
+}
+
+fn write_chunk(
+    pattern: &str,
+    file_type: &str,
+    index: usize,
+    width: usize,
+    content: &str,
+) -> Result<PathBuf> {
+    let path = chunk_path(pattern, file_type, index, width);
+    write_atomic(&path, content)?;
+    Ok(path)
+}
+
+/// Creates parent directories and writes `content` to `path`.
+fn write_atomic(path: &Path, content: &str) -> Result<()> {
+    let tmp = temp_path_for(path)?;
     if let Some(p) = path.parent() {
         fs::create_dir_all(p)?;
     }
-    fs::write(&path, content)?;
+    fs::write(&tmp, content).with_context(|| format!("Cannot write {:?}", tmp))?;
+    fs::rename(&tmp, path).with_context(|| format!("Cannot move output into {:?}", path))?;
+    Ok(())
+}
+
+/// Outputs are written to a sibling temp file and renamed into place, so a killed run never
+/// leaves a truncated file that looks complete.
+fn temp_path_for(path: &Path) -> Result<PathBuf> {
+    // The temp name adds a leading dot and `.tmp`, so it is the one that hits the limit first.
+    let name_len = path.file_name().map_or(0, |n| n.len());
+    if name_len + 5 > NAME_MAX {
+        anyhow::bail!(
+            "output filename too long ({} bytes, max {}): {:?}. Shorten the --out pattern.",
+            name_len,
+            NAME_MAX - 5,
+            path.file_name().unwrap_or_default()
+        );
+    }
+    Ok(path.with_file_name(format!(
+        ".{}.tmp",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    )))
+}
+
+/// Runs `--on-chunk` for a written chunk. `{}` in the command is replaced by the chunk path;
+/// without a placeholder the path is appended as the last argument.
+fn run_chunk_hook(cmd: &str, chunk: &Path) -> Result<()> {
+    let chunk = chunk.to_string_lossy();
+    let mut argv: Vec<String> = cmd
+        .split_whitespace()
+        .map(|a| a.replace("{}", &chunk))
+        .collect();
+    if argv.is_empty() {
+        anyhow::bail!("empty command");
+    }
+    if !cmd.contains("{}") {
+        argv.push(chunk.into_owned());
+    }
+    let status = Command::new(&argv[0])
+        .args(&argv[1..])
+        .status()
+        .with_context(|| format!("cannot run `{}`", argv[0]))?;
+    if !status.success() {
+        anyhow::bail!("`{}` exited with {}", argv.join(" "), status);
+    }
     Ok(())
 }
 
+/// Matches the file names `--out` produces: `{index}`/`*` become digits, `{type}` a label.
+fn chunk_name_regex(args: &Args) -> Option<Regex> {
+    let file_pattern = Path::new(&args.out)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    Regex::new(&format!(
+        "^{}$",
+        regex::escape(&file_pattern)
+            .replace(r"\{index\}", r"\d+")
+            .replace(r"\{type\}", r"[a-zA-Z0-9_-]+")
+            .replace(r"\*", r"\d+")
+    ))
+    .ok()
+}
+
+/// Whether `name` is something a run leaves in the output directory: a chunk, a temp file
+/// from an interrupted write, `checksums.txt`, the `--append` manifest, or a `--report`
+/// written there.
+fn is_output_artifact(args: &Args, name: &str) -> bool {
+    if args.report.as_deref().is_some_and(|r| {
+        r.file_name().is_some_and(|n| n == name)
+            && report_path(args).and_then(|r| r.parent().map(Path::to_path_buf))
+                == Some(canonical_output_dir(args))
+    }) {
+        return true;
+    }
+    let name = name
+        .strip_prefix('.')
+        .and_then(|n| n.strip_suffix(".tmp"))
+        .unwrap_or(name);
+    name == "checksums.txt"
+        || name == MANIFEST_NAME
+        || chunk_name_regex(args).is_some_and(|re| re.is_match(name))
+}
+
+/// The canonical `--report` file, unless the report goes to stderr.
+fn report_path(args: &Args) -> Option<PathBuf> {
+    args.report
+        .as_deref()
+        .filter(|r| *r != Path::new("-"))
+        .map(canonicalize_lenient)
+}
+
+/// An explicit `--output-extension` always wins; otherwise `--format` picks the suffix of the
+/// default name and of a pattern without one, so a user-chosen suffix is never rewritten.
+fn apply_output_extension(args: &mut Args, default_name: bool) {
+    let suffix = match &args.output_extension {
+        Some(ext) => Some(ext.trim_start_matches('.').to_string()),
+        None if default_name || Path::new(&args.out).extension().is_none() => {
+            Some(args.format.extension().to_string())
+        }
+        None => None,
+    };
+    if let Some(ext) = suffix {
+        let mut out = PathBuf::from(&args.out);
+        out.set_extension(ext);
+        args.out = out.to_string_lossy().into_owned();
+    }
+}
+
 fn prepare_output_directory(args: &Args) -> Result<()> {
     let path = Path::new(&args.out);
     let parent = path
         .parent()
         .filter(|p| !p.as_os_str().is_empty())
         .unwrap_or(Path::new("."));
-    if parent.exists() && !args.no_clean_out {
-        let file_pattern = path.file_name().unwrap_or_default().to_string_lossy();
-        let safe_regex = format!(
-            "^{}$",
-            regex::escape(&file_pattern)
-                .replace(r"\{index\}", r"\d+")
-                .replace(r"\{type\}", r"[a-zA-Z0-9_-]+")
-                .replace(r"\*", r"\d+")
-        );
-        if let Ok(re) = Regex::new(&safe_regex) {
-            for entry in fs::read_dir(parent)?.flatten() {
-                if let Ok(name) = entry.file_name().into_string() {
-                    if re.is_match(&name) {
-                        let _ = fs::remove_file(entry.path());
-                    }
+    if parent.exists() && !args.no_clean_out && !args.append {
+        let stale: Vec<PathBuf> = fs::read_dir(parent)?
+            .flatten()
+            .filter(|e| {
+                e.file_type().is_ok_and(|t| t.is_file())
+                    && is_output_artifact(args, &e.file_name().to_string_lossy())
+            })
+            .map(|e| e.path())
+            .collect();
+        if !stale.is_empty() && !args.yes {
+            confirm_wipe(parent, stale.len())?;
+        }
+        for path in stale {
+            let _ = fs::remove_file(path);
+        }
+    } else if !parent.exists() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(())
+}
+
+/// `--check`: everything short of writing. Patterns and regexes were already compiled by the
+/// caller (a bad one fails before this point); here the files are collected, the output
+/// target is validated, and the plan is printed. Any problem makes the run fail.
+fn run_check(args: &Args, base: &Path, canonical_base: &Path, rules: &CompiledRules) -> Result<()> {
+    let (files, collect_stats) = collect_files(args, base, rules)?;
+    let mut problems = Vec::new();
+    if files.is_empty() {
+        problems.push("no files match the current --type, include and exclude settings".into());
+    }
+    let target = match &args.mirror {
+        Some(dir) => dir.clone(),
+        None => {
+            let type_label = args.file_type.as_deref().unwrap_or("all");
+            let first = chunk_path(&args.out, type_label, 1, args.pad_index.unwrap_or(0));
+            if let Err(e) = temp_path_for(&first) {
+                problems.push(format!("{:#}", e));
+            }
+            if !args.allow_output_in_source {
+                if let Err(e) = check_output_location(args, canonical_base) {
+                    problems.push(format!("{:#}", e));
                 }
             }
+            first
+                .parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or(Path::new("."))
+                .to_path_buf()
+        }
+    };
+    let chunks = (files.iter().map(|f| f.size).sum::<u64>() as usize).div_ceil(args.limit.max(1));
+    if args.mirror.is_none() && args.limit > 0 && chunks > 1 {
+        let type_label = args.file_type.as_deref().unwrap_or("all");
+        let paths: Vec<PathBuf> = (1..=chunks)
+            .map(|i| chunk_path(&args.out, type_label, i, args.pad_index.unwrap_or(0)))
+            .collect();
+        if let Err(e) = check_chunk_paths(&args.out, &paths) {
+            problems.push(format!("{:#}", e));
+        }
+    }
+    if let Err(e) = check_writable(&target) {
+        problems.push(format!(
+            "output directory {:?} is not writable: {:#}",
+            target, e
+        ));
+    }
+    if collect_stats.unreadable_dirs > 0 {
+        problems.push(format!(
+            "{} director{} could not be read",
+            collect_stats.unreadable_dirs,
+            if collect_stats.unreadable_dirs == 1 {
+                "y"
+            } else {
+                "ies"
+            }
+        ));
+    }
+
+    let total: u64 = files.iter().map(|f| f.size).sum();
+    println!(
+        "🔎 Check: {} files, {} of input.",
+        files.len(),
+        format_size(total)
+    );
+    match &args.mirror {
+        Some(dir) => println!("   Would mirror into {:?}.", dir),
+        None if args.limit > 0 => println!(
+            "   Would write about {} chunk(s) of up to {} bytes to {:?}.",
+            (total as usize).div_ceil(args.limit).max(1),
+            args.limit,
+            args.out
+        ),
+        None => println!("   Would write a single chunk to {:?}.", args.out),
+    }
+    if problems.is_empty() {
+        println!("✅ Configuration is valid.");
+        return Ok(());
+    }
+    for p in &problems {
+        eprintln!("❌ {}", p);
+    }
+    anyhow::bail!("--check found {} problem(s)", problems.len())
+}
+
+/// Whether files can be created in `dir`, or, if it doesn't exist yet, in the nearest
+/// existing ancestor (the run would create the rest).
+fn check_writable(dir: &Path) -> Result<()> {
+    let mut existing = dir;
+    while !existing.exists() {
+        existing = match existing.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+    }
+    let probe = existing.join(".source-dumper-check.tmp");
+    fs::write(&probe, b"")?;
+    let _ = fs::remove_file(&probe);
+    Ok(())
+}
+
+/// Top-level system directories that are never a single project.
+const BROAD_DIRS: &[&str] = &[
+    "/bin",
+    "/etc",
+    "/lib",
+    "/opt",
+    "/proc",
+    "/sys",
+    "/usr",
+    "/var",
+    "/Applications",
+    "/Library",
+    "/System",
+    "/Users",
+];
+
+/// Refuses to walk the filesystem root, the home directory or one of its ancestors, or a
+/// top-level system directory: such a walk is almost always a typo'd `--path`.
+fn check_broad_scan(base: &Path) -> Result<()> {
+    let home = std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .and_then(|h| fs::canonicalize(h).ok());
+    let reason = if base.parent().is_none() {
+        "the filesystem root"
+    } else if home.as_deref() == Some(base) {
+        "your home directory"
+    } else if home.as_deref().is_some_and(|h| h.starts_with(base)) {
+        "a directory containing your home directory"
+    } else if BROAD_DIRS.iter().any(|d| base == Path::new(d)) {
+        "a system directory"
+    } else {
+        return Ok(());
+    };
+    anyhow::bail!(
+        "Refusing to scan {}: it is {}. Pass --allow-broad-scan if this is intended.",
+        base.display(),
+        reason
+    )
+}
+
+/// Refuses to write chunks into `--path` itself or any directory under it, so
+/// `--out src/main.txt` can't drop output among the code being read. A dedicated output
+/// directory under the project (the default `dump/`, holding nothing but earlier dumps) is
+/// fine; the walk skips it.
+fn check_output_location(args: &Args, base: &Path) -> Result<()> {
+    let dir = canonical_output_dir(args);
+    if !dir.starts_with(base) {
+        return Ok(());
+    }
+    let dedicated = dir != base
+        && fs::read_dir(&dir).map_or(true, |entries| {
+            entries
+                .flatten()
+                .all(|e| is_output_artifact(args, &e.file_name().to_string_lossy()))
+        });
+    if !dedicated {
+        anyhow::bail!(
+            "Refusing to write output into {:?}, which is inside --path {:?} and holds more \
+             than earlier dumps. Choose another --out or pass --allow-output-in-source.",
+            dir,
+            base
+        );
+    }
+    Ok(())
+}
+
+/// The canonical directory `--out` writes to.
+fn canonical_output_dir(args: &Args) -> PathBuf {
+    canonicalize_lenient(
+        Path::new(&args.out)
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new(".")),
+    )
+}
+
+/// Canonicalizes a path that may not exist yet: the nearest existing ancestor is
+/// canonicalized and the rest appended.
+fn canonicalize_lenient(path: &Path) -> PathBuf {
+    let mut dir = path;
+    let mut missing = Vec::new();
+    loop {
+        if let Ok(c) = fs::canonicalize(dir) {
+            return missing.iter().rev().fold(c, |c, name| c.join(name));
+        }
+        match (dir.parent(), dir.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                dir = Some(parent)
+                    .filter(|p| !p.as_os_str().is_empty())
+                    .unwrap_or(Path::new("."));
+            }
+            _ => return dir.to_path_buf(),
+        }
+    }
+}
+
+/// Writes the `--report` JSON describing the run itself (`-` prints it to stderr).
+fn write_report(
+    path: &Path,
+    base: &Path,
+    collected: usize,
+    stats: &ProcessingStats,
+    collect_stats: &CollectStats,
+    started: Instant,
+) -> Result<()> {
+    let skipped = SKIPPED.lock().unwrap_or_else(|e| e.into_inner());
+    let report = serde_json::json!({
+        "path": base.display().to_string(),
+        "arguments": std::env::args().skip(1).collect::<Vec<_>>(),
+        "files_collected": collected,
+        "files_processed": stats.files_processed,
+        "files_skipped": skipped
+            .iter()
+            .map(|(path, reason)| serde_json::json!({ "path": path, "reason": reason }))
+            .collect::<Vec<_>>(),
+        "unreadable_dirs": collect_stats.unreadable_dirs,
+        "over_max_lines": stats
+            .over_max_lines
+            .iter()
+            .map(|(path, lines)| serde_json::json!({ "path": path.display().to_string(), "lines": lines }))
+            .collect::<Vec<_>>(),
+        "outputs": stats
+            .outputs
+            .iter()
+            .map(|(path, bytes)| serde_json::json!({ "path": path.display().to_string(), "bytes": bytes }))
+            .collect::<Vec<_>>(),
+        "bytes_in": stats.bytes_in,
+        "bytes_out": stats.bytes_out,
+        "redactions": stats.redactions,
+        "elapsed_ms": started.elapsed().as_millis() as u64,
+    });
+    let text = serde_json::to_string_pretty(&report)? + "\n";
+    if path == Path::new("-") {
+        eprint!("{}", text);
+        Ok(())
+    } else {
+        write_atomic(path, &text)
+    }
+}
+
+/// Asks before deleting previous output. Non-interactive runs must opt in with `--yes`
+/// rather than hang on stdin.
+fn confirm_wipe(dir: &Path, count: usize) -> Result<()> {
+    // The prompt goes to stderr, so piping stdout does not hide it or mix it into the output.
+    if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+        anyhow::bail!(
+            "Refusing to delete {} existing output file(s) in {:?} without confirmation. \
+             Pass --yes to allow it or --no-clean-out to keep them.",
+            count,
+            dir
+        );
+    }
+    eprint!(
+        "Delete {} existing output file(s) in {:?}? [y/N] ",
+        count, dir
+    );
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        anyhow::bail!("Aborted: existing output files were left in place.");
+    }
+    Ok(())
+}
+
+/// Reads one pattern per line. Blank lines and `#` comments are skipped, including trailing
+/// comments after whitespace (`dist  # build output`).
+fn load_patterns_from_file(path: &Path) -> Result<Vec<String>> {
+    let file = File::open(path).with_context(|| format!("Cannot read {:?}", path))?;
+    Ok(BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .map(|l| {
+            let end = l
+                .char_indices()
+                .find(|&(i, c)| c == '#' && (i == 0 || l[..i].ends_with(char::is_whitespace)))
+                .map_or(l.len(), |(i, _)| i);
+            l[..end].trim().to_string()
+        })
+        .filter(|l| !l.is_empty())
+        .collect())
+}
+
+/// Reads a ripgrep/fd-style ignore file as exclude patterns. Gitignore's anchoring (`/dist`)
+/// and directory-only (`build/`) markers are dropped, since exclude patterns already match
+/// at any depth and apply to directories and files alike.
+fn load_ignore_file(path: &Path) -> Result<Vec<String>> {
+    Ok(load_patterns_from_file(path)?
+        .into_iter()
+        .map(|line| {
+            let (neg, pat) = match line.strip_prefix('!') {
+                Some(rest) => ("!", rest),
+                None => ("", line.as_str()),
+            };
+            format!(
+                "{}{}",
+                neg,
+                pat.trim_start_matches('/').trim_end_matches('/')
+            )
+        })
+        .filter(|p| !p.is_empty() && p != "!")
+        .collect())
+}
+
+/// Collects `.gitattributes` patterns marked `linguist-generated` or `linguist-vendored`
+/// (GitHub Linguist's convention) as exclude patterns.
+fn load_linguist_excludes(path: &Path) -> Result<Vec<String>> {
+    let mut patterns = Vec::new();
+    for line in load_patterns_from_file(path)? {
+        let mut parts = line.split_whitespace();
+        let Some(pattern) = parts.next() else {
+            continue;
+        };
+        let marked = parts.any(|attr| {
+            matches!(
+                attr,
+                "linguist-generated"
+                    | "linguist-generated=true"
+                    | "linguist-vendored"
+                    | "linguist-vendored=true"
+            )
+        });
+        if marked {
+            // Our globs already match at any depth and include directory contents.
+            let p = pattern.trim_start_matches('/');
+            let p = p.strip_suffix("/**").unwrap_or(p).trim_end_matches('/');
+            patterns.push(p.to_string());
+        }
+    }
+    Ok(patterns)
+}
+
+/// Reads submodule paths from `.gitmodules`. A missing file yields no submodules.
+fn load_submodule_paths(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    let mut paths = Vec::new();
+    for line in load_patterns_from_file(path)? {
+        if let Some((key, val)) = line.split_once('=') {
+            if key.trim() == "path" {
+                paths.push(PathBuf::from(val.trim().trim_matches('/')));
+            }
         }
-    } else if !parent.exists() {
-        fs::create_dir_all(parent)?;
     }
-    Ok(())
+    Ok(paths)
 }
 
 fn load_config_file(args: &mut Args, matches: &ArgMatches) -> Result<()> {
@@ -561,24 +4471,125 @@ fn load_config_file(args: &mut Args, matches: &ArgMatches) -> Result<()> {
         .clone()
         .unwrap_or_else(|| PathBuf::from(".dumperrc"));
     if !path.exists() {
+        if let Some(name) = &args.profile {
+            anyhow::bail!(
+                "--profile {:?} needs a config file, but {:?} does not exist",
+                name,
+                path
+            );
+        }
         return Ok(());
     }
-    let file = File::open(path)?;
+    let file = File::open(&path).with_context(|| format!("Cannot read {:?}", path))?;
+    // Top-level settings apply first; the `[profile.NAME]` section picked by --profile is
+    // applied over them. Other sections are ignored.
+    let mut base = Vec::new();
+    let mut selected = Vec::new();
+    let mut profiles = Vec::new();
+    let mut section: Option<String> = None;
     for line in BufReader::new(file).lines().map_while(Result::ok) {
         let line = line.trim();
         if line.is_empty() || line.starts_with('#') {
             continue;
         }
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = Some(header.trim().to_string());
+            if let Some(name) = header.trim().strip_prefix("profile.") {
+                profiles.push(name.to_string());
+            }
+            continue;
+        }
         let Some((k, v)) = line.split_once('=') else {
             continue;
         };
-        let (key, val) = (k.trim(), v.trim().trim_matches('"'));
-        let cli =
-            |id: &str| matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine);
+        let entry = (k.trim().to_string(), v.trim().trim_matches('"').to_string());
+        match (&section, &args.profile) {
+            (None, _) => base.push(entry),
+            (Some(s), Some(name)) if s.strip_prefix("profile.") == Some(name.as_str()) => {
+                selected.push(entry)
+            }
+            _ => {}
+        }
+    }
+    if let Some(name) = &args.profile {
+        if !profiles.contains(name) {
+            anyhow::bail!(
+                "Profile {:?} not found in {:?} (available: {})",
+                name,
+                path,
+                if profiles.is_empty() {
+                    "none".to_string()
+                } else {
+                    profiles.join(", ")
+                }
+            );
+        }
+    }
+    for (key, val) in base.iter().chain(&selected) {
+        let (key, val) = (key.as_str(), val.as_str());
+        // Environment variables rank with the command line: both override the config file.
+        let cli = |id: &str| {
+            matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        };
         match key {
             "path" if !cli("path") => args.path = PathBuf::from(val),
             "type" if !cli("file_type") => args.file_type = Some(val.to_string()),
             "out" if !cli("out") => args.out = val.to_string(),
+            "file_header_template" if !cli("file_header_template") => {
+                args.file_header_template = val.to_string()
+            }
+            "file_footer_template" if !cli("file_footer_template") => {
+                args.file_footer_template = val.to_string()
+            }
+            "follow_only_internal_links" if !args.follow_only_internal_links => {
+                args.follow_only_internal_links = val == "true"
+            }
+            "no_external_links" if !args.no_external_links => {
+                args.no_external_links = val == "true"
+            }
+            "max_lines" if !cli("max_lines") => {
+                if let Ok(n) = val.parse() {
+                    args.max_lines = Some(n)
+                }
+            }
+            "on_chunk" if !cli("on_chunk") => args.on_chunk = Some(val.to_string()),
+            "output_extension" if !cli("output_extension") => {
+                args.output_extension = Some(val.to_string())
+            }
+            "line_ending" if !cli("line_ending") => {
+                if let Ok(l) = LineEnding::from_str(val, true) {
+                    args.line_ending = l
+                }
+            }
+            "redact" if !args.redact => args.redact = val == "true",
+            "replace" => args.replace.push(val.to_string()),
+            "allow_broad_scan" if !args.allow_broad_scan => args.allow_broad_scan = val == "true",
+            "allow_output_in_source" if !args.allow_output_in_source => {
+                args.allow_output_in_source = val == "true"
+            }
+            "tree_file" if !cli("tree_file") => args.tree_file = Some(PathBuf::from(val)),
+            "mirror" if !cli("mirror") => args.mirror = Some(PathBuf::from(val)),
+            "include_submodules" if !args.include_submodules => {
+                args.include_submodules = val == "true"
+            }
+            // Regexes may contain commas, so each config line holds a single pattern.
+            "redact_pattern" => args.redact_pattern.push(val.to_string()),
+            "fail_on_pattern" => args.fail_on_pattern.push(val.to_string()),
+            "encoding" if !cli("encoding") => {
+                if let Ok(e) = Encoding::from_str(val, true) {
+                    args.encoding = e
+                }
+            }
+            "format" if !cli("format") => {
+                if let Ok(f) = OutputFormat::from_str(val, true) {
+                    args.format = f
+                }
+            }
+            "output_dir" if !cli("output_dir") => args.output_dir = Some(PathBuf::from(val)),
+            "out_name" if !cli("out_name") => args.out_name = val.to_string(),
             "limit" if !cli("limit") => {
                 if let Ok(l) = val.parse() {
                     args.limit = l
@@ -589,6 +4600,72 @@ fn load_config_file(args: &mut Args, matches: &ArgMatches) -> Result<()> {
                     args.max_file_size = s
                 }
             }
+            "context_file" => args.context_file.push(PathBuf::from(val)),
+            "path_as_comment" if !args.path_as_comment => args.path_as_comment = val == "true",
+            "append" if !args.append => args.append = val == "true",
+            "no_canonicalize" if !args.no_canonicalize => args.no_canonicalize = val == "true",
+            "report" if !cli("report") => args.report = Some(PathBuf::from(val)),
+            "interleave_tree" if !args.interleave_tree => args.interleave_tree = val == "true",
+            "min_file_size" if !cli("min_file_size") => {
+                if let Ok(n) = parse_size(val) {
+                    args.min_file_size = Some(n)
+                }
+            }
+            "checksums" if !args.checksums => args.checksums = val == "true",
+            "smart_excludes" if !args.smart_excludes => args.smart_excludes = val == "true",
+            "binary_as_base64" if !cli("binary_as_base64") => {
+                if let Ok(n) = val.parse() {
+                    args.binary_as_base64 = Some(n)
+                }
+            }
+            "max_parts" if !cli("max_parts") => {
+                if let Ok(n) = val.parse() {
+                    args.max_parts = Some(n)
+                }
+            }
+            "tree_sort" if !cli("tree_sort") => {
+                if let Ok(t) = TreeSort::from_str(val, true) {
+                    args.tree_sort = t
+                }
+            }
+            "sample" if !cli("sample") => {
+                if let Ok(n) = val.parse() {
+                    args.sample = Some(n)
+                }
+            }
+            "sample_mode" if !cli("sample_mode") => {
+                if let Ok(m) = SampleMode::from_str(val, true) {
+                    args.sample_mode = m
+                }
+            }
+            "seed" if !cli("seed") => {
+                if let Ok(n) = val.parse() {
+                    args.seed = Some(n)
+                }
+            }
+            "overlap_files" if !cli("overlap_files") => {
+                if let Ok(n) = val.parse() {
+                    args.overlap_files = n
+                }
+            }
+            "order_file" if !cli("order_file") => args.order_file = Some(PathBuf::from(val)),
+            "order_strict" if !args.order_strict => args.order_strict = val == "true",
+            "traversal" if !cli("traversal") => {
+                if let Ok(t) = Traversal::from_str(val, true) {
+                    args.traversal = Some(t)
+                }
+            }
+            "sort" if !cli("sort") => {
+                if let Ok(k) = SortKey::from_str(val, true) {
+                    args.sort = k
+                }
+            }
+            "reverse" if !args.reverse => args.reverse = val == "true",
+            "max_files" if !cli("max_files") => {
+                if let Ok(n) = val.parse() {
+                    args.max_files = Some(n)
+                }
+            }
             "tree_depth" if !cli("tree_depth") => {
                 if let Ok(d) = val.parse() {
                     args.tree_depth = Some(d)
@@ -601,11 +4678,93 @@ fn load_config_file(args: &mut Args, matches: &ArgMatches) -> Result<()> {
                 .include
                 .extend(val.split(',').map(|s| s.trim().to_string())),
             "clean" if !args.clean => args.clean = val == "true",
+            "clean_diff" if !args.clean_diff => args.clean_diff = val == "true",
             "progress" if !args.progress => args.progress = val == "true",
-            "verbose" if !args.verbose => args.verbose = val == "true",
+            "quiet" if !args.quiet => args.quiet = val == "true",
+            "progress_bytes" if !args.progress_bytes => args.progress_bytes = val == "true",
+            "verbose" if args.verbose == 0 => {
+                args.verbose = match val {
+                    "true" => 1,
+                    _ => val.parse().unwrap_or(0),
+                }
+            }
             "hidden" if !args.hidden => args.hidden = val == "true",
             "show_size" if !args.show_size => args.show_size = val == "true",
             "no_tree" if !args.no_tree => args.no_tree = val == "true",
+            "toc" if !args.toc => args.toc = val == "true",
+            "show_mtime" if !args.show_mtime => args.show_mtime = val == "true",
+            "skip_empty" if !args.skip_empty => args.skip_empty = val == "true",
+            "minify_data" if !args.minify_data => args.minify_data = val == "true",
+            "show_excluded" if !args.show_excluded => args.show_excluded = val == "true",
+            "git_info" if !args.git_info => args.git_info = val == "true",
+            "include_only" if !args.include_only => args.include_only = val == "true",
+            "trim_trailing" if !args.trim_trailing => args.trim_trailing = val == "true",
+            "wrap_width" if !cli("wrap_width") => {
+                if let Ok(n) = val.parse() {
+                    args.wrap_width = Some(n)
+                }
+            }
+            "tabs_to_spaces" if !cli("tabs_to_spaces") => {
+                if let Ok(n) = val.parse() {
+                    args.tabs_to_spaces = Some(n)
+                }
+            }
+            "part_headers" if !args.part_headers => args.part_headers = val == "true",
+            "balance_chunks" if !args.balance_chunks => args.balance_chunks = val == "true",
+            "reproducible" if !args.reproducible => args.reproducible = val == "true",
+            "exclude_generated" if !args.exclude_generated => {
+                args.exclude_generated = val == "true"
+            }
+            "delimiter_style" if !cli("delimiter_style") => {
+                if let Ok(d) = DelimiterStyle::from_str(val, true) {
+                    args.delimiter_style = d
+                }
+            }
+            "agent_sentinel" if !cli("agent_sentinel") => args.agent_sentinel = val.to_string(),
+            "treat_as" => args
+                .treat_as
+                .extend(val.split(',').map(|s| s.trim().to_string())),
+            "max_clean_size" if !cli("max_clean_size") => {
+                if let Ok(n) = parse_size(val) {
+                    args.max_clean_size = n
+                }
+            }
+            "clipboard" if !args.clipboard => args.clipboard = val == "true",
+            "skip_minified" if !args.skip_minified => args.skip_minified = val == "true",
+            "minified_threshold" if !cli("minified_threshold") => {
+                if let Ok(n) = val.parse() {
+                    args.minified_threshold = n
+                }
+            }
+            "timing" if !args.timing => args.timing = val == "true",
+            "pad_index" if !cli("pad_index") => {
+                if let Ok(w) = val.parse() {
+                    args.pad_index = Some(w)
+                }
+            }
+            "strip_imports" if !args.strip_imports => args.strip_imports = val == "true",
+            "chunk_by_dir" if !args.chunk_by_dir => args.chunk_by_dir = val == "true",
+            "comment_density" if !args.comment_density => args.comment_density = val == "true",
+            "extensions" if !args.extensions => args.extensions = val == "true",
+            "detect_language" if !args.detect_language => args.detect_language = val == "true",
+            "keep_empty_lines" if !args.keep_empty_lines => args.keep_empty_lines = val == "true",
+            "keep_comment_marker" => args
+                .keep_comment_marker
+                .extend(val.split(',').map(|s| s.trim().to_string())),
+            "clean_cmd" => args.clean_cmd.push(val.to_string()),
+            "generated_marker" => args
+                .generated_marker
+                .extend(val.split(',').map(|s| s.trim().to_string())),
+            "no_dumpignore" if !args.no_dumpignore => args.no_dumpignore = val == "true",
+            "banner" if !cli("banner") => args.banner = Some(val.to_string()),
+            "outline" if !args.outline => args.outline = val == "true",
+            "relativize_to" if !cli("relativize_to") => {
+                args.relativize_to = Some(PathBuf::from(val))
+            }
+            "no_ignore_files" if !args.no_ignore_files => args.no_ignore_files = val == "true",
+            "respect_gitattributes" if !args.respect_gitattributes => {
+                args.respect_gitattributes = val == "true"
+            }
             _ => {}
         }
     }
@@ -616,91 +4775,213 @@ fn load_config_file(args: &mut Args, matches: &ArgMatches) -> Result<()> {
 // TREE LOGIC
 // ============================================================================
 
-fn generate_tree(args: &Args, base: &Path, rules: &CompiledRules) -> String {
-    let mut stats = TreeStats::default();
-    let mut visited = HashSet::new();
-    let max = args
-        .tree_depth
-        .unwrap_or(DEFAULT_TREE_DEPTH)
-        .min(ABSOLUTE_MAX_DEPTH);
-
-    let mut ctx = TreeContext {
+fn tree_context<'a>(args: &Args, base: &'a Path, rules: &'a CompiledRules) -> TreeContext<'a> {
+    TreeContext {
         base,
         rules,
-        max_depth: max,
+        max_depth: args
+            .tree_depth
+            .unwrap_or(DEFAULT_TREE_DEPTH)
+            .min(ABSOLUTE_MAX_DEPTH),
         hidden: args.hidden,
         show_size: args.show_size,
-        visited: &mut visited,
-        stats: &mut stats,
-    };
+        sort: args.tree_sort,
+    }
+}
+
+/// Tree of one directory (relative to `base`) for `--interleave-tree`. Depth still counts
+/// from the root, so `--tree-depth` means the same as in the main tree.
+fn generate_dir_tree(args: &Args, base: &Path, rules: &CompiledRules, dir: &Path) -> String {
+    let ctx = tree_context(args, base, rules);
+    let (body, _) = walk_tree(&base.join(dir), "", 1, &HashSet::new(), &ctx).unwrap_or_default();
+    if args.format == OutputFormat::Xml {
+        return format!(
+            "<structure path=\"{}\">{}</structure>\n",
+            xml_escape(&dir.display().to_string()),
+            cdata(&body)
+        );
+    }
+    format!("\nDIRECTORY: {}/\n{}\n", dir.display(), body.trim_end())
+}
 
-    let body = walk_tree(base, "", 0, &mut ctx);
+fn generate_tree(args: &Args, base: &Path, rules: &CompiledRules) -> String {
+    let ctx = tree_context(args, base, rules);
 
+    let (body, stats) = if base.is_file() {
+        let size = fs::metadata(base).map(|m| m.len()).unwrap_or(0);
+        let line = format!(
+            "└── {}{}\n",
+            base.file_name().unwrap_or_default().to_string_lossy(),
+            if args.show_size {
+                format!(" ({})", format_size(size))
+            } else {
+                String::new()
+            }
+        );
+        let stats = TreeStats {
+            files: 1,
+            total_size: size,
+            ..Default::default()
+        };
+        (line, stats)
+    } else {
+        walk_tree(base, "", 0, &HashSet::new(), &ctx).unwrap_or_default()
+    };
     format!(
         "PROJECT STRUCTURE: {:?}\n{}\n{}\n{}\n{} dirs, {} files, {} total\n{}\n",
-        base,
+        root_label(args, base),
         "=".repeat(40),
         body.trim_end(),
         "=".repeat(40),
-        ctx.stats.dirs,
-        ctx.stats.files,
-        format_size(ctx.stats.total_size),
+        stats.dirs,
+        stats.files,
+        format_size(stats.total_size),
         "=".repeat(40)
     )
 }
 
-fn walk_tree(dir: &Path, prefix: &str, depth: usize, ctx: &mut TreeContext) -> String {
+/// Renders `dir` and its subtree. Sibling subdirectories are walked in parallel and stitched
+/// back together in sorted order. Cycle detection tracks the canonical paths of the current
+/// branch's ancestors, so it stays correct without sharing state between threads.
+/// Fails only if `dir` itself cannot be read, so the caller can annotate it.
+fn walk_tree(
+    dir: &Path,
+    prefix: &str,
+    depth: usize,
+    ancestors: &HashSet<PathBuf>,
+    ctx: &TreeContext,
+) -> std::io::Result<(String, TreeStats)> {
     if depth > ctx.max_depth {
-        return format!("{}... (max depth)\n", prefix);
+        return Ok((format!("{}... (max depth)\n", prefix), TreeStats::default()));
     }
+    let mut ancestors = ancestors.clone();
     if let Ok(c) = fs::canonicalize(dir) {
-        if !ctx.visited.insert(c) {
-            return String::new();
+        if !ancestors.insert(c) {
+            return Ok((String::new(), TreeStats::default()));
         }
     }
 
-    let mut out = String::new();
-    let Ok(entries) = fs::read_dir(dir) else {
-        return out;
-    };
+    let entries = fs::read_dir(dir)?;
     let mut entries: Vec<_> = entries
         .flatten()
         .filter(|e| !is_excluded(&e.path(), ctx.base, ctx.rules, ctx.hidden))
         .collect();
-    entries.sort_by_key(|e| e.file_name());
+    match ctx.sort {
+        TreeSort::Name => entries.sort_by_key(|e| e.file_name()),
+        TreeSort::NameCi => entries.sort_by_key(|e| {
+            let name = e.file_name();
+            (name.to_string_lossy().to_lowercase(), name)
+        }),
+        TreeSort::Type => entries.sort_by_key(|e| (!e.path().is_dir(), e.file_name())),
+    }
 
     let count = entries.len();
-    for (i, e) in entries.into_iter().enumerate() {
-        let is_last = i == count - 1;
-        let path = e.path();
-        let name = e.file_name().to_string_lossy().to_string();
-
-        let size = path.metadata().map(|m| m.len()).unwrap_or(0);
-        let size_info = if ctx.show_size && path.is_file() {
-            format!(" ({})", format_size(size))
-        } else {
-            String::new()
-        };
+    let rendered: Vec<(String, TreeStats)> = entries
+        .par_iter()
+        .enumerate()
+        .map(|(i, e)| {
+            let is_last = i == count - 1;
+            let path = e.path();
+            let name = e.file_name().to_string_lossy().to_string();
+            let mut stats = TreeStats::default();
 
-        out.push_str(&format!(
-            "{}{}{}{}{}\n",
-            prefix,
-            if is_last { "└── " } else { "├── " },
-            name,
-            if path.is_dir() { "/" } else { "" },
-            size_info
-        ));
+            let size = path.metadata().map(|m| m.len()).unwrap_or(0);
+            let size_info = if ctx.show_size && path.is_file() {
+                format!(" ({})", format_size(size))
+            } else {
+                String::new()
+            };
 
-        if path.is_dir() {
-            ctx.stats.dirs += 1;
-            let next_prefix = format!("{}{}", prefix, if is_last { "    " } else { "│   " });
-            out.push_str(&walk_tree(&path, &next_prefix, depth + 1, ctx));
-        } else {
-            ctx.stats.files += 1;
-            ctx.stats.total_size += size;
-        }
+            let mut suffix = size_info;
+            let mut sub = String::new();
+            if path.is_dir() {
+                let next_prefix = format!("{}{}", prefix, if is_last { "    " } else { "│   " });
+                stats.dirs = 1;
+                match walk_tree(&path, &next_prefix, depth + 1, &ancestors, ctx) {
+                    Ok((text, sub_stats)) => {
+                        sub = text;
+                        stats.dirs += sub_stats.dirs;
+                        stats.files = sub_stats.files;
+                        stats.total_size = sub_stats.total_size;
+                    }
+                    Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                        suffix.push_str(" [permission denied]");
+                    }
+                    Err(_) => {}
+                }
+            } else {
+                stats.files = 1;
+                stats.total_size = size;
+            }
+
+            let out = format!(
+                "{}{}{}{}{}\n{}",
+                prefix,
+                if is_last { "└── " } else { "├── " },
+                name,
+                if path.is_dir() { "/" } else { "" },
+                suffix,
+                sub
+            );
+            (out, stats)
+        })
+        .collect();
+
+    let mut out = String::new();
+    let mut stats = TreeStats::default();
+    for (text, s) in rendered {
+        out.push_str(&text);
+        stats.dirs += s.dirs;
+        stats.files += s.files;
+        stats.total_size += s.total_size;
     }
-    out
+    Ok((out, stats))
+}
+
+/// Formats a timestamp as ISO-8601 UTC (`2024-05-01T12:34:56Z`) without a date crate.
+fn format_timestamp(time: std::time::SystemTime) -> String {
+    let secs = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Civil-from-days (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Parses a byte count with an optional binary unit: `512`, `4K`, `1.5MB`, `2GiB`.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num.parse().map_err(|_| format!("invalid size {:?}", s))?;
+    let mult = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1u64,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        other => return Err(format!("unknown size unit {:?}", other)),
+    };
+    Ok((num * mult as f64) as u64)
 }
 
 fn format_size(bytes: u64) -> String {
@@ -728,16 +5009,14 @@ fn cmd_init(force: bool, output: &Path) -> Result<()> {
 }
 
 fn cmd_config(args: &Args, diff: bool, matches: &ArgMatches) -> Result<()> {
-    println!("Active Configuration (* = overridden by CLI):");
-    let cli = |id: &str| {
-        if matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine) {
-            "*"
-        } else {
-            " "
-        }
+    println!("Active Configuration (* = overridden by CLI, e = set from the environment):");
+    let cli = |id: &str| match matches.value_source(id) {
+        Some(ValueSource::CommandLine) => "*",
+        Some(ValueSource::EnvVariable) => "e",
+        _ => " ",
     };
     let print = |k: &str, v: String, id: &str| {
-        if !diff || cli(id) == "*" {
+        if !diff || cli(id) != " " {
             println!("{} {:15} = {}", cli(id), k, v);
         }
     };
@@ -746,6 +5025,835 @@ fn cmd_config(args: &Args, diff: bool, matches: &ArgMatches) -> Result<()> {
     print("out", args.out.clone(), "out");
     print("limit", args.limit.to_string(), "limit");
     print("clean", args.clean.to_string(), "clean");
+    print("sort", format!("{:?}", args.sort), "sort");
+    print("profile", format!("{:?}", args.profile), "profile");
     println!("   Excludes: {:?}", args.exclude);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collected(path: &str, size: u64) -> CollectedFile {
+        CollectedFile {
+            path: PathBuf::from(path),
+            display_path: PathBuf::from(path),
+            size,
+            tag: None,
+            forced: false,
+        }
+    }
+
+    fn args(flags: &[&str]) -> Args {
+        Args::try_parse_from(std::iter::once("source-dumper").chain(flags.iter().copied()))
+            .expect("valid arguments")
+    }
+
+    /// Sections shaped like `render_section` output: `--- FILE` header, body, blank line.
+    fn sections(count: usize, body_len: usize) -> Vec<Section> {
+        (0..count)
+            .map(|i| {
+                let path = PathBuf::from(format!("src/f{}.rs", i));
+                let text = format!(
+                    "\n--- FILE: {} ---\n{}\n",
+                    path.display(),
+                    "x".repeat(body_len)
+                );
+                Section::new(path, text)
+            })
+            .collect()
+    }
+
+    fn rules() -> CompiledRules {
+        CompiledRules {
+            exclude_globs: Vec::new(),
+            negated_globs: Vec::new(),
+            include_globs: Vec::new(),
+            negated_include_globs: Vec::new(),
+            grep: None,
+            clean_cmds: HashMap::new(),
+            redact: Vec::new(),
+            fail_on: Vec::new(),
+            submodules: Vec::new(),
+            replacements: Vec::new(),
+            comment_fallback: CommentStyle::C,
+            treat_as: Vec::new(),
+        }
+    }
+
+    /// A fresh directory under the system temp dir holding `files` (relative paths).
+    fn fixture(name: &str, files: &[&str]) -> PathBuf {
+        let files: Vec<(&str, &str)> = files.iter().map(|f| (*f, "fn x() {}\n")).collect();
+        fixture_with(name, &files)
+    }
+
+    fn fixture_with(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "source-dumper-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        for (file, content) in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, content).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        fs::canonicalize(&dir).unwrap()
+    }
+
+    /// Runs a full dump of `base` into a sibling directory and returns the chunks in order.
+    fn dump(base: &Path, flags: &[&str]) -> Vec<String> {
+        let out_dir = base.with_extension("out");
+        let _ = fs::remove_dir_all(&out_dir);
+        let out = out_dir.join("d_{index}.txt");
+        let mut all = vec![
+            "--path",
+            base.to_str().unwrap(),
+            "--out",
+            out.to_str().unwrap(),
+        ];
+        all.push("--quiet");
+        all.extend_from_slice(flags);
+        let args = args(&all);
+        let rules = rules();
+        let (mut files, _) = collect_files(&args, base, &rules).unwrap();
+        sort_files(&mut files, SortKey::Path, false, None);
+        let stats = process_files(&args, &files, base, &rules, &Manifest::default()).unwrap();
+        let chunks = stats
+            .outputs
+            .iter()
+            .map(|(path, _)| fs::read_to_string(path).unwrap())
+            .collect();
+        fs::remove_dir_all(&out_dir).unwrap();
+        chunks
+    }
+
+    fn collected_paths(args: &Args, base: &Path, rules: &CompiledRules) -> Vec<String> {
+        let (mut files, _) = collect_files(args, base, rules).unwrap();
+        sort_files(&mut files, SortKey::Path, false, None);
+        files
+            .iter()
+            .map(|f| f.display_path.to_string_lossy().replace('\\', "/"))
+            .collect()
+    }
+
+    fn paths(files: &[CollectedFile]) -> Vec<&str> {
+        files
+            .iter()
+            .map(|f| f.display_path.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn sort_size_reverse_puts_largest_first() {
+        let mut files = vec![
+            collected("b.rs", 10),
+            collected("a.rs", 300),
+            collected("c.rs", 20),
+            collected("d.rs", 300),
+        ];
+        sort_files(&mut files, SortKey::Size, true, None);
+        // Equal sizes fall back to the path, reversed along with everything else.
+        assert_eq!(paths(&files), ["d.rs", "a.rs", "c.rs", "b.rs"]);
+    }
+
+    #[test]
+    fn sort_size_reverse_stays_within_directories_under_traversal() {
+        let mut files = vec![
+            collected("src/small.rs", 1),
+            collected("src/big.rs", 900),
+            collected("lib/mid.rs", 50),
+            collected("lib/huge.rs", 5000),
+        ];
+        sort_files(&mut files, SortKey::Size, true, Some(Traversal::Depth));
+        assert_eq!(
+            paths(&files),
+            ["lib/huge.rs", "lib/mid.rs", "src/big.rs", "src/small.rs"]
+        );
+    }
+
+    #[test]
+    fn limit_zero_writes_a_single_part() {
+        let parts = plan_parts(&args(&["--limit", "0"]), 500, &sections(40, 200));
+        assert_eq!(parts, vec![0..40]);
+    }
+
+    #[test]
+    fn keep_comment_marker_keeps_todo_and_drops_noise() {
+        let args = args(&["--clean", "--keep-comment-marker"]);
+        let src = "fn a() {\n    // TODO: fix this\n    // noise\n    b();\n}\n";
+        let cleaned = clean_content(&args, Path::new("a.rs"), src, &rules());
+        assert!(cleaned.contains("// TODO: fix this"));
+        assert!(!cleaned.contains("noise"));
+        assert!(cleaned.contains("b();"));
+    }
+
+    #[test]
+    fn include_only_ignores_type() {
+        let base = fixture(
+            "include-only",
+            &["src/a.rs", "src/b.rs", "Makefile", "notes.txt"],
+        );
+        let args = args(&["--type", "rs", "--include-only", "--include", "Makefile"]);
+        let mut rules = rules();
+        rules.include_globs = compile_globs(&args.include);
+        assert_eq!(collected_paths(&args, &base, &rules), ["Makefile"]);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn no_file_section_is_split_across_parts() {
+        let args = args(&["--limit", "1000"]);
+        let sections = sections(25, 300);
+        let parts = plan_parts(&args, 200, &sections);
+        assert!(parts.len() > 1);
+        // Parts tile the sections in order, so every section lands whole in exactly one part.
+        let mut next = 0;
+        for part in &parts {
+            assert_eq!(part.start, next);
+            assert!(!part.is_empty());
+            next = part.end;
+            let rendered = render_part(&args, Path::new("."), 1, 1, "", &sections[part.clone()], 1);
+            let headers = rendered.matches("--- FILE: ").count();
+            assert_eq!(headers, part.len());
+            assert_eq!(rendered.matches('x').count(), part.len() * 300);
+        }
+        assert_eq!(next, sections.len());
+    }
+
+    #[test]
+    fn minify_yaml_keeps_block_scalars_intact() {
+        let src =
+            "a: 1   \n\nscript: |\n  echo one  \n\n  echo two\nfolded: >-\n  x\n\n  y\nb:   2\n\n";
+        assert_eq!(
+            minify_yaml(src),
+            "a: 1\nscript: |\n  echo one  \n\n  echo two\nfolded: >-\n  x\n\n  y\nb:   2"
+        );
+    }
+
+    #[test]
+    fn crlf_content_is_cleaned_and_collapsed() {
+        let lf_src = "fn a() {\n\n\n\n    // note\n    b();\n}\n";
+        let crlf = fixture_with("crlf", &[("a.rs", &lf_src.replace('\n', "\r\n"))]);
+        let lf = fixture_with("crlf-lf", &[("a.rs", lf_src)]);
+        let expected = dump(&lf, &["--no-tree", "--clean"]);
+        assert!(expected[0].contains("fn a() {\n\n    b();\n}"));
+        // CRLF input cleans and collapses exactly like LF input...
+        let normalized = dump(&crlf, &["--no-tree", "--clean", "--line-ending", "lf"]);
+        assert_eq!(normalized, expected);
+        // ...and `keep` restores CRLF only afterwards, with no stray `\r` left behind.
+        let kept = dump(&crlf, &["--no-tree", "--clean"]);
+        assert!(kept[0].contains("fn a() {\r\n\r\n    b();\r\n}"));
+        assert!(!kept[0].replace("\r\n", "").contains('\r'));
+        fs::remove_dir_all(crlf).unwrap();
+        fs::remove_dir_all(lf).unwrap();
+    }
+
+    #[test]
+    fn output_inside_source_is_refused_unless_dedicated() {
+        let base = fixture(
+            "out-guard",
+            &["src/main.rs", "dump/dump_1.txt", "dump/checksums.txt"],
+        );
+        let path = base.to_str().unwrap();
+        let out = |out: &str| args(&["--path", path, "--out", out]);
+        let base_out = base.join("notes_*.txt");
+        assert!(check_output_location(&out(base_out.to_str().unwrap()), &base).is_err());
+        let src_out = base.join("src/notes_*.txt");
+        assert!(check_output_location(&out(src_out.to_str().unwrap()), &base).is_err());
+        let dump_out = base.join("dump/dump_*.txt");
+        assert!(check_output_location(&out(dump_out.to_str().unwrap()), &base).is_ok());
+        let new_out = base.join("fresh/out/dump_*.txt");
+        assert!(check_output_location(&out(new_out.to_str().unwrap()), &base).is_ok());
+        fs::write(base.join("dump/notes.md"), "mine").unwrap();
+        assert!(check_output_location(&out(dump_out.to_str().unwrap()), &base).is_err());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn walker_skips_the_output_directory() {
+        let base = fixture("out-skip", &["src/main.rs", "dump/dump_1.txt"]);
+        let out = base.join("dump/dump_*.txt");
+        let args = args(&[
+            "--path",
+            base.to_str().unwrap(),
+            "--out",
+            out.to_str().unwrap(),
+        ]);
+        assert_eq!(collected_paths(&args, &base, &rules()), ["src/main.rs"]);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn base64_section_round_trips() {
+        let base = fixture("base64", &[]);
+        let bytes: Vec<u8> = (0..=255u8).cycle().take(300).collect();
+        fs::write(base.join("icon.png"), &bytes).unwrap();
+        let chunks = dump(&base, &["--binary-as-base64"]);
+        let chunk = chunks.concat();
+        let header = chunk
+            .lines()
+            .position(|l| l.contains("icon.png (base64)"))
+            .expect("base64 header");
+        let encoded: String = chunk
+            .lines()
+            .skip(header + 1)
+            .take_while(|l| !l.is_empty())
+            .collect();
+        assert_eq!(BASE64.decode(encoded).unwrap(), bytes);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn dump_artifacts_are_never_collected() {
+        let base = fixture(
+            "artifacts",
+            &[
+                "main.rs",
+                "dump_1.txt",
+                "checksums.txt",
+                ".dump-manifest",
+                "run.json",
+            ],
+        );
+        let path = base.to_str().unwrap();
+        let out = base.join("dump_*.txt");
+        let report = base.join("run.json");
+        let args = args(&[
+            "--path",
+            path,
+            "--out",
+            out.to_str().unwrap(),
+            "--report",
+            report.to_str().unwrap(),
+            "--hidden",
+            "--allow-output-in-source",
+        ]);
+        assert_eq!(collected_paths(&args, &base, &rules()), ["main.rs"]);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn artifacts_are_wiped_with_the_chunks() {
+        let base = fixture(
+            "wipe",
+            &[
+                "dump/dump_1.txt",
+                "dump/checksums.txt",
+                "dump/run.json",
+                "dump/keep.md",
+            ],
+        );
+        let out = base.join("dump/dump_*.txt");
+        let report = base.join("dump/run.json");
+        let args = args(&[
+            "--out",
+            out.to_str().unwrap(),
+            "--report",
+            report.to_str().unwrap(),
+            "--yes",
+        ]);
+        prepare_output_directory(&args).unwrap();
+        let left: Vec<_> = fs::read_dir(base.join("dump"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(left, ["keep.md"]);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn overlong_output_names_are_rejected_before_writing() {
+        let base = fixture("long-name", &[]);
+        let longest = base.join("a".repeat(NAME_MAX - 5));
+        assert_eq!(
+            temp_path_for(&longest).unwrap().file_name().unwrap().len(),
+            NAME_MAX
+        );
+        write_atomic(&longest, "ok").unwrap();
+        assert_eq!(fs::read_to_string(&longest).unwrap(), "ok");
+
+        let too_long = base.join("a".repeat(NAME_MAX - 4));
+        let err = write_atomic(&too_long, "no").unwrap_err();
+        assert!(format!("{:#}", err).contains("output filename too long"));
+        assert!(!too_long.exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn negated_exclude_reincludes_a_fixture_under_an_excluded_dir() {
+        let base = fixture(
+            "reinclude",
+            &[
+                "src/lib.rs",
+                "tests/it.rs",
+                "tests/fixtures/important.json",
+                "tests/fixtures/other.json",
+            ],
+        );
+        let args = args(&["--path", base.to_str().unwrap()]);
+        let rules = CompiledRules {
+            exclude_globs: compile_globs(&["tests".to_string()]),
+            negated_globs: compile_globs(&["tests/fixtures/important.json".to_string()]),
+            ..rules()
+        };
+        assert_eq!(
+            collected_paths(&args, &base, &rules),
+            ["src/lib.rs", "tests/fixtures/important.json"]
+        );
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn manifest_is_wiped_and_never_dumped_by_the_next_run() {
+        let base = fixture("manifest", &["main.rs"]);
+        let out = base.join("dump/dump_*.txt");
+        let run = |extra: &[&str]| {
+            let mut flags = vec![
+                "--path",
+                base.to_str().unwrap(),
+                "--out",
+                out.to_str().unwrap(),
+                "--hidden",
+                "--yes",
+                "--quiet",
+            ];
+            flags.extend_from_slice(extra);
+            let args = args(&flags);
+            prepare_output_directory(&args).unwrap();
+            let previous = read_manifest(&manifest_path(&args)).unwrap_or_default();
+            let (files, _) = collect_files(&args, &base, &rules()).unwrap();
+            let files: Vec<_> = files
+                .into_iter()
+                .filter(|f| !previous.files.contains(&f.display_path))
+                .collect();
+            assert!(paths(&files).iter().all(|p| *p == "main.rs"));
+            process_files(&args, &files, &base, &rules(), &previous).unwrap();
+            read_manifest(&manifest_path(&args)).unwrap()
+        };
+        assert_eq!(run(&[]).files, [PathBuf::from("main.rs")]);
+        assert_eq!(run(&[]).files, [PathBuf::from("main.rs")]);
+        let appended = run(&["--append"]);
+        assert_eq!(appended.files, [PathBuf::from("main.rs")]);
+        assert_eq!(appended.chunks, 1);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn keep_empty_lines_keeps_blank_lines_and_drops_comments() {
+        let src =
+            "use a;\n\n\n// helper\nfn a() {\n    b(); // call\n\n    /* gone */\n    c();\n}\n";
+        let args = args(&["--clean", "--keep-empty-lines"]);
+        assert_eq!(
+            clean_content(&args, Path::new("a.rs"), src, &rules()),
+            "use a;\n\n\nfn a() {\n    b(); \n\n    c();\n}"
+        );
+    }
+
+    #[test]
+    fn strip_imports_per_language() {
+        let cases = [
+            (
+                "a.rs",
+                "use std::fs;\npub use crate::{\n    a,\n    b,\n};\nextern crate x;\n\nfn main() {}\n",
+                "fn main() {}\n",
+            ),
+            (
+                "a.py",
+                "import os\nfrom x import (\n    a,\n    b,\n)\nfrom y import z\n\ndef f():\n    pass\n",
+                "def f():\n    pass\n",
+            ),
+            (
+                "a.ts",
+                "import { a } from './a';\nimport b from \"b\"\nconst c = require('c');\n\nexport const d = 1;\n",
+                "export const d = 1;\n",
+            ),
+            (
+                "a.go",
+                "package main\n\nimport (\n\t\"fmt\"\n\t\"os\"\n)\nimport x \"y\"\n\nfunc main() {}\n",
+                "package main\n\n\nfunc main() {}\n",
+            ),
+            (
+                "A.java",
+                "import java.util.List;\nimport static a.B.c;\n\nclass A {}\n",
+                "class A {}\n",
+            ),
+            (
+                "a.c",
+                "#include <stdio.h>\n# include \"a.h\"\n\nint main() {}\n",
+                "int main() {}\n",
+            ),
+            (
+                "a.php",
+                "use App\\Model;\nrequire_once 'x.php';\n\nclass A {}\n",
+                "class A {}\n",
+            ),
+            (
+                "a.cs",
+                "using System;\nusing static System.Math;\nusing L = System.Collections.Generic.List<int>;\n\nclass A {}\n",
+                "class A {}\n",
+            ),
+            (
+                "a.rb",
+                "require 'json'\nrequire_relative 'b'\n\nclass A; end\n",
+                "class A; end\n",
+            ),
+        ];
+        for (path, src, expected) in cases {
+            assert_eq!(
+                strip_imports(Path::new(path), src.to_string(), &rules()),
+                expected,
+                "{}",
+                path
+            );
+        }
+        // Unknown extensions are left alone.
+        let text = "import nothing\n";
+        assert_eq!(
+            strip_imports(Path::new("a.txt"), text.into(), &rules()),
+            text
+        );
+    }
+
+    #[test]
+    fn agent_bytes_count_the_encoded_content() {
+        let content = "fn é() {\n    x();\n}";
+        let base = fixture_with("agent-bytes", &[("a.rs", content)]);
+        let chunk = dump(
+            &base,
+            &[
+                "--no-tree",
+                "--delimiter-style",
+                "agent",
+                "--line-ending",
+                "crlf",
+                "--encoding",
+                "ascii",
+            ],
+        )
+        .concat();
+        let header = chunk
+            .find("<<<FILE path=a.rs bytes=")
+            .expect("agent header");
+        let rest = &chunk[header..];
+        let (line, body) = rest.split_once("\r\n").unwrap();
+        let bytes: usize = line
+            .rsplit('=')
+            .next()
+            .unwrap()
+            .trim_end_matches('>')
+            .parse()
+            .unwrap();
+        assert_eq!(&body[..bytes], "fn \\u00E9() {\r\n    x();\r\n}");
+        assert!(body[bytes..].starts_with("\r\n<<<ENDFILE>>>"));
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn hash_inside_python_strings_survives_cleaning() {
+        let src = concat!(
+            "def f():\n",
+            "    \"\"\"Docs # not a comment\n",
+            "    still # docs\"\"\"\n",
+            "    a = r'raw # kept'  # dropped\n",
+            "    b = f\"{x['#']} # kept\"\n",
+            "    c = b'''bytes # kept'''\n",
+        );
+        let args = args(&["--clean"]);
+        let cleaned = clean_content(&args, Path::new("a.py"), src, &rules());
+        assert!(cleaned.contains("\"\"\"Docs # not a comment\n    still # docs\"\"\""));
+        assert!(cleaned.contains("a = r'raw # kept'"));
+        assert!(!cleaned.contains("dropped"));
+        assert!(cleaned.contains("b = f\"{x['#']} # kept\""));
+        assert!(cleaned.contains("c = b'''bytes # kept'''"));
+    }
+
+    #[test]
+    fn pattern_files_strip_comments_and_keep_negations() {
+        let base = fixture_with(
+            "pattern-file",
+            &[(
+                "patterns",
+                "# header\ndist  # build output\n!dist/keep.js\n\nlib#v2\n",
+            )],
+        );
+        assert_eq!(
+            load_patterns_from_file(&base.join("patterns")).unwrap(),
+            ["dist", "!dist/keep.js", "lib#v2"]
+        );
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn conflicting_include_and_exclude_rules() {
+        let base = fixture(
+            "precedence",
+            &[
+                "src/a.rs",
+                "vendor/patched.js",
+                "vendor/old.js",
+                "vendor/lib.rs",
+            ],
+        );
+        let args = args(&["--path", base.to_str().unwrap()]);
+        let globs =
+            |p: &[&str]| compile_globs(&p.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+
+        // A matching include wins over an exclude; the rest of the directory stays excluded.
+        let include_wins = CompiledRules {
+            exclude_globs: globs(&["vendor"]),
+            include_globs: globs(&["vendor/patched.js"]),
+            ..rules()
+        };
+        assert_eq!(
+            collected_paths(&args, &base, &include_wins),
+            ["src/a.rs", "vendor/patched.js"]
+        );
+
+        // A `!` include stops the include from forcing a path back in.
+        let negated_include = CompiledRules {
+            exclude_globs: globs(&["vendor"]),
+            include_globs: globs(&["vendor/*.js"]),
+            negated_include_globs: globs(&["vendor/old.js"]),
+            ..rules()
+        };
+        assert_eq!(
+            collected_paths(&args, &base, &negated_include),
+            ["src/a.rs", "vendor/patched.js"]
+        );
+
+        // A `!` exclude re-includes what a positive exclude caught.
+        let negated_exclude = CompiledRules {
+            exclude_globs: globs(&["*.js"]),
+            negated_globs: globs(&["vendor/old.js"]),
+            ..rules()
+        };
+        assert_eq!(
+            collected_paths(&args, &base, &negated_exclude),
+            ["src/a.rs", "vendor/lib.rs", "vendor/old.js"]
+        );
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn colliding_chunk_paths_are_reported() {
+        let paths: Vec<PathBuf> = (1..=3)
+            .map(|i| chunk_path("dump/out.txt", "rs", i, 0))
+            .collect();
+        let err = check_chunk_paths("dump/out.txt", &paths).unwrap_err();
+        assert!(format!("{:#}", err).contains("dump/out.txt <- chunks 1, 2, 3"));
+        let paths: Vec<PathBuf> = (1..=3)
+            .map(|i| chunk_path("dump/{type}.txt", "rs", i, 0))
+            .collect();
+        assert!(check_chunk_paths("dump/{type}.txt", &paths).is_err());
+        let paths: Vec<PathBuf> = (1..=3)
+            .map(|i| chunk_path("dump/out_*.txt", "rs", i, 0))
+            .collect();
+        assert!(check_chunk_paths("dump/out_*.txt", &paths).is_ok());
+    }
+
+    #[test]
+    fn colliding_pattern_never_overwrites_a_chunk() {
+        let base = fixture_with(
+            "collide",
+            &[("a.rs", &"a".repeat(200)), ("b.rs", &"b".repeat(200))],
+        );
+        let out_dir = base.with_extension("out");
+        let _ = fs::remove_dir_all(&out_dir);
+        let out = out_dir.join("out.txt");
+        let args = args(&[
+            "--path",
+            base.to_str().unwrap(),
+            "--out",
+            out.to_str().unwrap(),
+            "--limit",
+            "250",
+            "--no-tree",
+            "--quiet",
+        ]);
+        let (files, _) = collect_files(&args, &base, &rules()).unwrap();
+        let Err(err) = process_files(&args, &files, &base, &rules(), &Manifest::default()) else {
+            panic!("colliding chunks were written");
+        };
+        assert!(format!("{:#}", err).contains("<- chunks 1, 2"));
+        // Streaming has written the first chunk by then, but it is never overwritten.
+        let first = fs::read_to_string(&out).unwrap();
+        assert_eq!(first.matches("--- FILE:").count(), 1);
+        let _ = fs::remove_dir_all(&out_dir);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_internal_links_are_followed() {
+        use std::os::unix::fs::symlink;
+        let base = fixture("links", &["src/a.rs"]);
+        let outside = fixture("links-outside", &["secret.rs"]);
+        symlink(base.join("src/a.rs"), base.join("link.rs")).unwrap();
+        symlink(outside.join("secret.rs"), base.join("ext.rs")).unwrap();
+        symlink(&outside, base.join("ext")).unwrap();
+        let path = base.to_str().unwrap();
+        assert_eq!(
+            collected_paths(&args(&["--path", path]), &base, &rules()),
+            ["ext/secret.rs", "ext.rs", "link.rs", "src/a.rs"]
+        );
+        let internal = args(&["--path", path, "--follow-only-internal-links"]);
+        assert_eq!(
+            collected_paths(&internal, &base, &rules()),
+            ["link.rs", "src/a.rs"]
+        );
+        fs::remove_dir_all(&base).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_internal_links_are_followed_under_include_roots() {
+        use std::os::unix::fs::symlink;
+        let base = fixture("links-base", &["main.rs"]);
+        let root = fixture("links-root", &["a.rs"]);
+        let outside = fixture("links-root-outside", &["secret.rs"]);
+        symlink(&outside, root.join("ext")).unwrap();
+        let flags = [
+            "--path",
+            base.to_str().unwrap(),
+            "--include",
+            root.to_str().unwrap(),
+        ];
+        let name = root.file_name().unwrap().to_str().unwrap();
+        let external = |p: &str| format!("[external]/{}/{}", name, p);
+        assert_eq!(
+            collected_paths(&args(&flags), &base, &rules()),
+            [
+                external("a.rs"),
+                external("ext/secret.rs"),
+                "main.rs".into()
+            ]
+        );
+        let internal = args(&[&flags[..], &["--follow-only-internal-links"]].concat());
+        assert_eq!(
+            collected_paths(&internal, &base, &rules()),
+            [external("a.rs"), "main.rs".into()]
+        );
+        for dir in [base, root, outside] {
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn clean_diff_is_not_cut_by_strip_imports() {
+        let src = "use std::fs;\n\n// helper\nfn a() {}\n";
+        let base = fixture_with("clean-diff", &[("a.rs", src)]);
+        // `main` turns --clean on for --clean-diff; the helper skips that step.
+        let chunk = dump(
+            &base,
+            &["--no-tree", "--clean", "--clean-diff", "--strip-imports"],
+        )
+        .concat();
+        assert!(chunk.contains("\n use std::fs;\n"));
+        assert!(chunk.contains("\n-// helper\n"));
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn format_sets_the_suffix_of_a_custom_pattern_without_one() {
+        let with = |flags: &[&str], default_name: bool| {
+            let mut args = args(flags);
+            apply_output_extension(&mut args, default_name);
+            args.out
+        };
+        assert_eq!(
+            with(&["--format", "xml", "--out", "out/ctx_*"], false),
+            "out/ctx_*.xml"
+        );
+        assert_eq!(
+            with(&["--format", "xml", "--out", "out/ctx_*.txt"], false),
+            "out/ctx_*.txt"
+        );
+        assert_eq!(with(&["--format", "xml"], true), "dump/dump_*.xml");
+        assert_eq!(
+            with(
+                &["--out", "out/ctx_*.txt", "--output-extension", ".log"],
+                false
+            ),
+            "out/ctx_*.log"
+        );
+    }
+
+    #[test]
+    fn max_lines_skips_are_recorded_with_their_line_counts() {
+        let base = fixture_with(
+            "max-lines",
+            &[("long.rs", "a\nb\nc\nd\n"), ("short.rs", "a\n")],
+        );
+        let out = base.with_extension("out").join("d_*.txt");
+        let args = args(&[
+            "--path",
+            base.to_str().unwrap(),
+            "--out",
+            out.to_str().unwrap(),
+            "--max-lines",
+            "2",
+            "--quiet",
+        ]);
+        let (files, _) = collect_files(&args, &base, &rules()).unwrap();
+        let stats = process_files(&args, &files, &base, &rules(), &Manifest::default()).unwrap();
+        assert_eq!(stats.over_max_lines, [(PathBuf::from("long.rs"), 4)]);
+        assert_eq!(stats.files_processed, 1);
+        fs::remove_dir_all(base.with_extension("out")).unwrap();
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn max_files_caps_external_include_roots() {
+        let base = fixture("cap-base", &["main.rs"]);
+        let root = fixture("cap-root", &["a.rs", "b.rs", "c.rs"]);
+        let flags = [
+            "--path",
+            base.to_str().unwrap(),
+            "--include",
+            root.to_str().unwrap(),
+        ];
+        let capped = args(&[&flags[..], &["--max-files", "2"]].concat());
+        let (files, _) = collect_files(&capped, &base, &rules()).unwrap();
+        assert_eq!(files.len(), 2);
+        let small = args(&[&flags[..], &["--min-file-size", "1000"]].concat());
+        let (files, stats) = collect_files(&small, &base, &rules()).unwrap();
+        assert!(files.is_empty());
+        assert_eq!(stats.small_files, 4);
+        fs::remove_dir_all(&base).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn file_path_keeps_external_includes() {
+        let base = fixture("file-path", &["main.rs"]);
+        let root = fixture("file-path-root", &["a.rs"]);
+        let extra = fixture("file-path-extra", &["notes.md"]);
+        let file = base.join("main.rs");
+        let notes = extra.join("notes.md");
+        let args = args(&[
+            "--path",
+            file.to_str().unwrap(),
+            "--include",
+            root.to_str().unwrap(),
+            "--include",
+            notes.to_str().unwrap(),
+        ]);
+        let name = root.file_name().unwrap().to_str().unwrap();
+        assert_eq!(
+            collected_paths(&args, &file, &rules()),
+            [
+                "[external]/notes.md".into(),
+                format!("[external]/{}/a.rs", name),
+                "main.rs".into()
+            ]
+        );
+        for dir in [base, root, extra] {
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}